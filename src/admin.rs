@@ -0,0 +1,473 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+
+use futures::future::join_all;
+
+use ruma::{OwnedRoomId, RoomAliasId, RoomId};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::AppState;
+use crate::appservice::RoomSummary;
+use crate::cache::{CACHE_CATEGORIES, CacheError, CacheKey, encode_room_id, key_category};
+use crate::error::AppserviceError;
+use crate::policy::{self, PolicyViolation};
+use crate::roomlist::{RoomListKind, RoomLists};
+use crate::rooms::fetch_and_process_rooms;
+
+/// Join reasons are kept around for as long as the room is likely to stay
+/// joined, they're only used for reporting.
+const JOIN_REASON_TTL: u64 = 60 * 60 * 24 * 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinReason {
+    Invite,
+    WorldReadable,
+    SpaceChild,
+    PublicRoomEvent,
+    Admin,
+}
+
+pub async fn record_join_reason(state: &AppState, room_id: &RoomId, reason: JoinReason) {
+    let cache_key = ("appservice:join_reason", room_id.as_str()).cache_key();
+    if let Err(e) = state
+        .cache
+        .cache_data(&cache_key, &reason, JOIN_REASON_TTL)
+        .await
+    {
+        tracing::warn!("Failed to record join reason for room {}: {}", room_id, e);
+    }
+}
+
+async fn join_reason(state: &AppState, room_id: &RoomId) -> Option<JoinReason> {
+    let cache_key = ("appservice:join_reason", room_id.as_str()).cache_key();
+    state
+        .cache
+        .get_cached_data::<JoinReason>(&cache_key)
+        .await
+        .ok()
+        .flatten()
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminRoom {
+    pub room_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_alias: Option<String>,
+    pub join_reason: Option<JoinReason>,
+    pub public: bool,
+    pub violations: Vec<PolicyViolation>,
+}
+
+async fn admin_room_summary(state: &AppState, room_id: OwnedRoomId) -> AdminRoom {
+    let summary = state.appservice.get_room_summary(room_id.clone()).await;
+    if let Err(e) = &summary {
+        tracing::warn!("Failed to fetch room summary for {}: {}", room_id, e);
+    }
+    let summary = summary.unwrap_or_default();
+
+    let decision = policy::check_room(state, &room_id).await;
+
+    AdminRoom {
+        room_id: room_id.to_string(),
+        name: summary.name,
+        canonical_alias: summary.canonical_alias,
+        join_reason: join_reason(state, &room_id).await,
+        public: decision.is_public(),
+        violations: decision.violations,
+    }
+}
+
+pub async fn rooms(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    let joined_rooms = state.appservice.joined_rooms().await.map_err(|e| {
+        tracing::error!("Failed to fetch joined rooms: {}", e);
+        AppserviceError::HomeserverError("Failed to fetch joined rooms".to_string())
+    })?;
+
+    let semaphore = Arc::new(Semaphore::new(10));
+    let room_futures: Vec<_> = joined_rooms
+        .into_iter()
+        .map(|room_id| {
+            let sem = semaphore.clone();
+            let state = state.clone();
+            async move {
+                let _permit = sem.acquire().await.ok()?;
+                Some(admin_room_summary(&state, room_id).await)
+            }
+        })
+        .collect();
+
+    let rooms: Vec<AdminRoom> = join_all(room_futures).await.into_iter().flatten().collect();
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "total": rooms.len(),
+            "rooms": rooms,
+        })),
+    ))
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminRoomDetails {
+    pub room_id: String,
+    pub joined: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<RoomSummary>,
+    pub join_reason: Option<JoinReason>,
+    pub public: bool,
+    pub violations: Vec<PolicyViolation>,
+    pub children: Vec<String>,
+}
+
+pub async fn room(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    let room_id = RoomId::parse(&room_id).map_err(|e| {
+        tracing::error!("Invalid room ID: {}", &room_id);
+        AppserviceError::MatrixError(format!("Invalid room ID: {e}"))
+    })?;
+
+    let joined = state
+        .appservice
+        .has_joined_room(&room_id)
+        .await
+        .unwrap_or(false);
+
+    let summary = match joined {
+        true => state
+            .appservice
+            .get_room_summary(room_id.clone())
+            .await
+            .ok(),
+        false => None,
+    };
+
+    let children = match joined {
+        true => state
+            .appservice
+            .get_room_hierarchy(room_id.clone())
+            .await
+            .map(|rooms| {
+                rooms
+                    .into_iter()
+                    .map(|room| room.summary.room_id)
+                    .filter(|child| *child != room_id)
+                    .map(|child| child.to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        false => Vec::new(),
+    };
+
+    let decision = policy::check_room(&state, &room_id).await;
+
+    let details = AdminRoomDetails {
+        room_id: room_id.to_string(),
+        joined,
+        summary,
+        join_reason: join_reason(&state, &room_id).await,
+        public: decision.is_public(),
+        violations: decision.violations,
+        children,
+    };
+
+    Ok((StatusCode::OK, Json(json!(details))))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheKeysParams {
+    pub prefix: Option<String>,
+    pub limit: Option<usize>,
+}
+
+pub async fn cache_stats(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(json!({
+        "categories": state.cache.stats.snapshot(),
+    }))
+}
+
+pub async fn cache_keys(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CacheKeysParams>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    let prefix = params.prefix.unwrap_or_default();
+    let limit = params.limit.unwrap_or(1000);
+
+    let mut keys = state.cache.keys_with_prefix(&prefix).await.map_err(|e| {
+        tracing::error!("Failed to list cache keys: {}", e);
+        AppserviceError::AppserviceError("Failed to list cache keys".to_string())
+    })?;
+
+    keys.sort();
+    let total = keys.len();
+    keys.truncate(limit);
+
+    Ok(Json(json!({
+        "total": total,
+        "keys": keys,
+    })))
+}
+
+pub async fn purge_room_cache(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    let room_id = RoomId::parse(&room_id).map_err(|e| {
+        tracing::error!("Invalid room ID: {}", &room_id);
+        AppserviceError::MatrixError(format!("Invalid room ID: {e}"))
+    })?;
+
+    let purged = purge_room(&state, &room_id).await.map_err(|e| {
+        tracing::error!("Failed to purge cache for room {}: {}", room_id, e);
+        AppserviceError::AppserviceError("Failed to purge room cache".to_string())
+    })?;
+
+    Ok(Json(json!({
+        "purged": purged,
+    })))
+}
+
+/// Deletes every cached entry that belongs to a room, including proxied
+/// responses where the room ID appears percent-encoded in the URL.
+pub async fn purge_room(state: &AppState, room_id: &RoomId) -> Result<usize, CacheError> {
+    let mut keys = state.cache.keys_containing(room_id.as_str()).await?;
+    keys.extend(
+        state
+            .cache
+            .keys_containing(&encode_room_id(room_id.as_str()))
+            .await?,
+    );
+
+    // the join reason isn't cached data, keep it for reporting
+    keys.retain(|key| key_category(key) != "appservice:join_reason");
+    keys.sort();
+    keys.dedup();
+
+    state.cache.delete_keys(&keys).await
+}
+
+pub async fn flush_cache_category(
+    State(state): State<Arc<AppState>>,
+    Path(category): Path<String>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    if !CACHE_CATEGORIES.contains(&category.as_str()) {
+        return Err(AppserviceError::AppserviceError(format!(
+            "Unknown cache category: {category}"
+        )));
+    }
+
+    let flushed = state.cache.delete_by_prefix(&category).await.map_err(|e| {
+        tracing::error!("Failed to flush cache category {}: {}", category, e);
+        AppserviceError::AppserviceError("Failed to flush cache category".to_string())
+    })?;
+
+    tracing::info!("Flushed {} keys in cache category {}", flushed, category);
+
+    Ok(Json(json!({
+        "flushed": flushed,
+    })))
+}
+
+pub async fn refresh_public_rooms(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    if state.index.is_ready() {
+        let rooms = state.index.rebuild(&state).await;
+        return Ok(Json(json!({
+            "rooms": rooms,
+        })));
+    }
+
+    let rooms = fetch_and_process_rooms(state.clone()).await;
+
+    state
+        .cache
+        .cache_rooms(&rooms, state.config.cache.public_rooms.ttl)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to cache public rooms: {}", e);
+            AppserviceError::AppserviceError("Failed to cache public rooms".to_string())
+        })?;
+
+    Ok(Json(json!({
+        "rooms": rooms.len(),
+    })))
+}
+
+pub async fn refresh_spaces(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    for prefix in ["space_summary", "space_rooms"] {
+        if let Err(e) = state.cache.delete_by_prefix(prefix).await {
+            tracing::warn!("Failed to flush cache category {}: {}", prefix, e);
+        }
+    }
+
+    let spaces = state
+        .appservice
+        .get_public_spaces()
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to get public spaces: {}", e);
+            AppserviceError::AppserviceError("Failed to get public spaces".to_string())
+        })?
+        .unwrap_or_default();
+
+    state
+        .cache
+        .cache_public_spaces(&spaces, state.config.spaces.ttl)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to cache public spaces: {}", e);
+            AppserviceError::AppserviceError("Failed to cache public spaces".to_string())
+        })?;
+
+    Ok(Json(json!({
+        "spaces": spaces.len(),
+    })))
+}
+
+pub async fn room_lists(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(json!(state.room_lists.lists()))
+}
+
+/// Adds a room ID or alias to the blocklist or allowlist. Blocking a room
+/// leaves it and drops everything served for it straight away.
+pub async fn add_room_list_entry(
+    State(state): State<Arc<AppState>>,
+    Path((kind, entry)): Path<(RoomListKind, String)>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    let room_id = resolve_room_list_entry(&state, &entry).await?;
+
+    let listed = update_room_lists(&state, |lists| {
+        lists.insert(kind, entry, room_id.to_string())
+    })
+    .await?;
+
+    tracing::info!(
+        "Added {} ({}) to the {:?}",
+        listed.entry,
+        listed.room_id,
+        kind
+    );
+
+    policy::invalidate(&state, room_id.as_str()).await;
+
+    if kind == RoomListKind::Blocklist {
+        block_room(&state, &room_id).await;
+    }
+
+    Ok(Json(json!(listed)))
+}
+
+pub async fn remove_room_list_entry(
+    State(state): State<Arc<AppState>>,
+    Path((kind, entry)): Path<(RoomListKind, String)>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    let removed = update_room_lists(&state, |lists| lists.remove(kind, &entry)).await?;
+    if removed.is_empty() {
+        return Err(AppserviceError::AppserviceError(format!(
+            "Not on the {kind:?}: {entry}"
+        )));
+    }
+
+    for listed in &removed {
+        tracing::info!(
+            "Removed {} ({}) from the {:?}",
+            listed.entry,
+            listed.room_id,
+            kind
+        );
+        policy::invalidate(&state, &listed.room_id).await;
+    }
+
+    Ok(Json(json!({
+        "removed": removed,
+    })))
+}
+
+async fn resolve_room_list_entry(
+    state: &AppState,
+    entry: &str,
+) -> Result<OwnedRoomId, AppserviceError> {
+    if entry.starts_with('#') {
+        let alias = RoomAliasId::parse(entry)
+            .map_err(|e| AppserviceError::InvalidParam(format!("Invalid room alias: {e}")))?;
+
+        return state
+            .appservice
+            .room_id_from_alias(alias)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to resolve room alias {}: {}", entry, e);
+                AppserviceError::MatrixError(format!("Failed to resolve room alias: {e}"))
+            });
+    }
+
+    RoomId::parse(entry).map_err(|e| AppserviceError::InvalidParam(format!("Invalid room ID: {e}")))
+}
+
+async fn update_room_lists<T>(
+    state: &AppState,
+    change: impl FnOnce(&RoomLists) -> T,
+) -> Result<T, AppserviceError> {
+    state
+        .room_lists
+        .update(&state.cache, change)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to persist room lists: {}", e);
+            AppserviceError::AppserviceError("Failed to persist room lists".to_string())
+        })
+}
+
+/// Leaves a blocked room and forgets everything about it. A failure in any
+/// step is logged and the rest still run, the blocklist keeps the room out
+/// either way.
+async fn block_room(state: &AppState, room_id: &OwnedRoomId) {
+    // the leave is always sent, the tracked joined rooms can miss some, and it
+    // failing for a room that was never joined is harmless
+    let joined = state.appservice.joined_room_ids().contains(room_id);
+    if let Err(e) = state
+        .appservice
+        .reject_invite(room_id, &policy::InviteRejection::Blocked.to_string())
+        .await
+    {
+        match joined {
+            true => tracing::warn!("Failed to leave blocked room {}: {}", room_id, e),
+            false => tracing::info!("Not leaving blocked room {}, not joined: {}", room_id, e),
+        }
+    }
+
+    if let Err(e) = state.appservice.remove_from_joined_rooms(room_id) {
+        tracing::warn!("Failed to forget blocked room {}: {}", room_id, e);
+    }
+
+    match purge_room(state, room_id).await {
+        Ok(purged) => tracing::info!("Purged {} cached keys for blocked room {}", purged, room_id),
+        Err(e) => tracing::warn!("Failed to purge cache for blocked room {}: {}", room_id, e),
+    }
+
+    if state.index.remove_room(room_id.as_str()) {
+        state.index.persist_room(state, room_id.as_str()).await;
+    }
+
+    if let Some(search) = &state.search {
+        search.remove_room(room_id.as_str());
+    }
+}
@@ -1,13 +1,9 @@
-use axum::{Json, extract::State, http::StatusCode};
-
-use ruma::events::AnyStateEvent;
-use ruma::events::room::redaction::RoomRedactionEvent;
-use ruma::events::room::{
-    history_visibility::{HistoryVisibility, RoomHistoryVisibilityEvent},
-    member::{MembershipState, RoomMemberEvent},
-    message::RoomMessageEvent,
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
 };
-use ruma::events::space::child::SpaceChildEvent;
+
 use std::time::Duration;
 
 use http::Method;
@@ -20,7 +16,8 @@ use std::sync::Arc;
 
 use crate::AppState;
 
-use crate::cache::CacheKey;
+use crate::cache::{CacheEntry, CacheKey};
+use crate::requests::CachedResponse;
 
 #[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
 #[ruma_event(type = "commune.public.room", kind = State, state_key_type = String)]
@@ -30,212 +27,53 @@ pub struct CommunePublicRoomEventContent {
 
 pub async fn transactions(
     State(state): State<Arc<AppState>>,
+    Path(txn_id): Path<String>,
     Json(payload): Json<Value>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    let events = match payload.get("events") {
-        Some(Value::Array(events)) => events,
-        Some(_) | None => {
-            tracing::info!("Events is not an array");
-            return Ok(Json(json!({})));
-        }
-    };
-
-    for event in events {
-        if cfg!(debug_assertions) {
-            tracing::info!("Event: {:#?}", event);
-        }
+    let cache_key = ("appservice:txn", txn_id.as_str()).cache_key();
 
-        // If auto-join is enabled, join rooms with world_readable history visibility
-        if state.config.appservice.rules.auto_join {
-            if let Ok(event) = serde_json::from_value::<RoomHistoryVisibilityEvent>(event.clone()) {
-                if event.history_visibility() == &HistoryVisibility::WorldReadable {
-                    tracing::info!("History Visibility: World Readable");
-
-                    tokio::spawn(async move {
-                        // Join the room if history visibility is world readable
-                        // delay for a moment to allow the event to be processed
-                        tokio::time::sleep(Duration::from_secs(5)).await;
-
-                        let room_id = event.room_id().to_owned();
-                        tracing::info!("Joining room: {}", room_id);
-                        if let Err(e) = state.appservice.join_room(&room_id).await {
-                            tracing::warn!("Failed to join room: {}. Error: {}", room_id, e);
-                        } else {
-                            tracing::info!("Successfully joined room: {}", room_id);
-                        }
-                    });
-
-                    return Ok(Json(json!({})));
-                }
-            }
-
-            if let Ok(event) = serde_json::from_value::<SpaceChildEvent>(event.clone()) {
-                tracing::info!("Auto joining space child room");
-
-                tokio::spawn(async move {
-                    let room_id = event.room_id().to_owned();
-                    tracing::info!("Joining room: {}", room_id);
-                    if let Err(e) = state.appservice.join_room(&room_id).await {
-                        tracing::warn!("Failed to join room: {}. Error: {}", room_id, e);
-                    } else {
-                        tracing::info!("Successfully joined room: {}", room_id);
-                    }
-                });
-
-                return Ok(Json(json!({})));
-            }
-        };
-
-        let public = event["content"]["public"].as_bool();
-        if let Ok(event) = serde_json::from_value::<CommunePublicRoomEvent>(event.clone()) {
-            tracing::info!("Commune Public room event.");
-            let room_id = event.room_id().to_owned();
-            match public {
-                Some(true) => {
-                    tracing::info!("Joining room: {}", room_id);
-                    let joined = state.appservice.join_room(&room_id).await;
-                    // cache the joined status
-                    if let Ok(joined) = joined {
-                        let cache_key = ("appservice:joined", room_id.as_str()).cache_key();
-                        if (state.cache.cache_data(&cache_key, &joined, 300).await).is_ok() {
-                            tracing::info!("Cached joined status for room: {}", room_id);
-                        } else {
-                            tracing::warn!("Failed to cache joined status for room: {}", room_id);
-                        }
-                    }
-                }
-                Some(false) => {
-                    tracing::info!("Leaving room: {}", room_id);
-                    if let Err(e) = state.appservice.leave_room(&room_id).await {
-                        tracing::warn!("Failed to leave room: {}. Error: {}", room_id, e);
-                    } else {
-                        tracing::info!("Successfully left room: {}", room_id);
-                    }
-                    let cache_key = ("appservice:joined", room_id.as_str()).cache_key();
-                    if let Err(e) = state.cache.delete_cached_data(&cache_key).await {
-                        tracing::warn!(
-                            "Failed to delete room from cache: {}. Error: {}",
-                            room_id,
-                            e
-                        );
-                    } else {
-                        tracing::info!("Successfully removed room from cache: {}", room_id);
-                    }
-                }
-                None => {}
-            }
-        };
+    // The homeserver retries transactions it didn't get a response for, so
+    // replay the stored result instead of running the side effects again
+    if let Ok(Some(result)) = state.cache.get_cached_data::<Value>(&cache_key).await {
+        tracing::info!("Transaction {} already processed", txn_id);
+        return Ok(Json(result));
+    }
 
-        if let Ok(event) = serde_json::from_value::<AnyStateEvent>(event.clone()) {
-            println!("State Event: {:#?}", event);
-        };
+    let Json(result) = process_transaction(state.clone(), payload).await?;
 
-        if let Ok(event) = serde_json::from_value::<RoomRedactionEvent>(event.clone()) {
-            let room_id = event.room_id().to_owned().to_string();
-            let state = state.clone();
-            tokio::spawn(async move {
-                handle_recache(state, room_id, true).await;
-            });
-        }
+    if let Err(e) = state
+        .cache
+        .cache_data(&cache_key, &result, state.config.appservice.transaction_ttl)
+        .await
+    {
+        tracing::warn!("Failed to store transaction {}: {}", txn_id, e);
+    }
 
-        if let Ok(event) = serde_json::from_value::<RoomMessageEvent>(event.clone()) {
-            let room_id = event.room_id().to_owned().to_string();
-            let state = state.clone();
-            tokio::spawn(async move {
-                handle_recache(state, room_id, false).await;
-            });
-        }
+    Ok(Json(result))
+}
 
-        let member_event =
-            if let Ok(event) = serde_json::from_value::<RoomMemberEvent>(event.clone()) {
-                event
-            } else {
-                continue;
-            };
-
-        println!("Member Event: {member_event:#?}");
-
-        let room_id = member_event.room_id().to_owned();
-        let membership = member_event.membership().to_owned();
-        let server_name = member_event.room_id().server_name();
-
-        match server_name {
-            Some(server_name) => {
-                let allowed = state
-                    .config
-                    .appservice
-                    .rules
-                    .federation_domain_whitelist
-                    .iter()
-                    .any(|domain| server_name.as_str().ends_with(domain));
-
-                if server_name.as_str() != state.config.matrix.server_name && allowed {
-                    // Ignore events for rooms on other servers, if configured to local homeserver
-                    // users
-                    if state.config.appservice.rules.invite_by_local_user {
-                        tracing::info!(
-                            "Ignoring event for room on different server: {}",
-                            server_name
-                        );
-                        continue;
-                    }
-                }
-            }
-            None => {
-                tracing::info!("Ignoring event for room with no server name");
-                continue;
-            }
+async fn process_transaction(
+    state: Arc<AppState>,
+    payload: Value,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let events = match payload.get("events") {
+        Some(Value::Array(events)) => events.clone(),
+        Some(_) | None => {
+            tracing::info!("Events is not an array");
+            return Ok(Json(json!({})));
         }
+    };
 
-        // Ignore membership events for other users
-        let invited_user = member_event.state_key().to_owned();
-        if invited_user != state.appservice.user_id() {
-            tracing::info!("Ignoring event for user: {}", invited_user);
-            continue;
-        }
+    let failures = state.dispatcher.dispatch(state.clone(), events).await;
 
-        match membership {
-            MembershipState::Invite => {
-                tracing::info!("Joining room: {}", room_id);
-                if let Err(e) = state.appservice.join_room(&room_id).await {
-                    tracing::warn!("Failed to join room: {}. Error: {}", room_id, e);
-                } else {
-                    tracing::info!("Successfully joined room: {}", room_id);
-                }
-                if state
-                    .appservice
-                    .add_to_joined_rooms(room_id.clone())
-                    .is_err()
-                {
-                    tracing::warn!("Failed to add room to joined rooms list: {}", room_id);
-                }
-            }
-            MembershipState::Leave => {
-                if let Err(e) = state.appservice.leave_room(&room_id).await {
-                    tracing::warn!("Failed to leave room: {}. Error: {}", room_id, e);
-                } else {
-                    tracing::info!("Successfully left room: {}", room_id);
-                }
-                if let Err(e) = state.appservice.remove_from_joined_rooms(&room_id) {
-                    tracing::warn!(
-                        "Failed to remove room from joined rooms list: {} {}",
-                        room_id,
-                        e
-                    );
-                }
-            }
-            MembershipState::Ban => {
-                tracing::info!("Banned from room: {}", room_id);
-                if let Err(e) = state.appservice.remove_from_joined_rooms(&room_id) {
-                    tracing::warn!(
-                        "Failed to remove room from joined rooms list: {} {}",
-                        room_id,
-                        e
-                    );
-                }
-            }
-            _ => {}
-        }
+    for failure in &failures {
+        tracing::warn!(
+            "Handler '{}' failed for {} event {:?}: {}",
+            failure.handler,
+            failure.event_type,
+            failure.event_id,
+            failure.error
+        );
     }
 
     Ok(Json(json!({})))
@@ -280,21 +118,36 @@ pub async fn refresh_messages_cache(
 
     let response = request_builder.send().await?;
 
+    let status = response.status();
+    let headers = response.headers().clone();
     let body = response.bytes().await?;
 
-    let data = body.to_vec();
+    if !status.is_success() {
+        tracing::warn!(
+            "Not recaching messages for room {}, homeserver returned {}",
+            room_id,
+            status
+        );
+        return Ok(());
+    }
+
+    let data = CachedResponse::new(status, &headers, body.to_vec());
 
     let key = ("proxy_request", url.as_str()).cache_key();
-    let ttl = state.config.cache.messages.ttl;
+    let options = &state.config.cache.messages;
 
-    let threshold: u64 = state.config.cache.messages.ttl - state.config.cache.messages.refresh_ttl;
+    // stored in the same envelope as proxied responses, so the stored TTL
+    // includes the stale window
+    let entry = CacheEntry::new(data, options.ttl);
+    let ttl = options.ttl + options.stale_ttl;
+    let threshold: u64 = ttl.saturating_sub(options.refresh_ttl);
 
     let res = match is_redaction {
-        true => state.cache.cache_data::<Vec<u8>>(&key, &data, ttl).await,
+        true => state.cache.cache_data(&key, &entry, ttl).await,
         false => {
             state
                 .cache
-                .cache_with_ttl_threshold::<Vec<u8>>(&key, data, ttl, threshold)
+                .cache_with_ttl_threshold(&key, entry, ttl, threshold)
                 .await
         }
     };
@@ -305,3 +158,89 @@ pub async fn refresh_messages_cache(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheBackendKind;
+    use crate::events::{EventDispatcher, EventHandler, TransactionEvent};
+    use crate::testing::{config_with_rules, state_event};
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingHandler(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl EventHandler for CountingHandler {
+        fn name(&self) -> &'static str {
+            "counting"
+        }
+
+        fn handles(&self, _state: &AppState, _event: &TransactionEvent) -> bool {
+            true
+        }
+
+        async fn handle(
+            &self,
+            _state: Arc<AppState>,
+            _event: &TransactionEvent,
+        ) -> Result<(), anyhow::Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    // answers just enough for the appservice client to start up
+    async fn fake_homeserver() -> String {
+        let app = axum::Router::new()
+            .route(
+                "/_matrix/client/versions",
+                axum::routing::get(|| async { Json(json!({ "versions": ["v1.11"] })) }),
+            )
+            .route(
+                "/_matrix/client/v3/account/whoami",
+                axum::routing::get(|| async { Json(json!({ "user_id": "@public:test.local" })) }),
+            );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn test_transactions_are_processed_once() {
+        let mut config = config_with_rules("");
+        config.cache.backend = CacheBackendKind::Memory;
+        config.matrix.homeserver = fake_homeserver().await;
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let mut dispatcher = EventDispatcher::new();
+        dispatcher.register(CountingHandler(handled.clone()));
+
+        let mut state = (*AppState::new(config).await.unwrap()).clone();
+        state.dispatcher = dispatcher;
+        let state = Arc::new(state);
+
+        let payload = json!({
+            "events": [state_event("m.room.topic", "", json!({ "topic": "Hello" }))],
+        });
+
+        let send = |txn_id: &str| {
+            transactions(
+                State(state.clone()),
+                Path(txn_id.to_string()),
+                Json(payload.clone()),
+            )
+        };
+
+        let Json(first) = send("txn1").await.unwrap();
+        let Json(replayed) = send("txn1").await.unwrap();
+        assert_eq!(first, replayed);
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+
+        assert!(send("txn2").await.is_ok());
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+    }
+}
@@ -111,6 +111,14 @@ impl AppService {
         Ok(())
     }
 
+    /// Rooms the appservice user is in, as tracked from transactions.
+    pub fn joined_room_ids(&self) -> Vec<OwnedRoomId> {
+        self.joined_rooms
+            .lock()
+            .map(|rooms| rooms.clone())
+            .unwrap_or_default()
+    }
+
     pub fn remove_from_joined_rooms(&self, room_id: &OwnedRoomId) -> Result<(), anyhow::Error> {
         let mut rooms = self
             .joined_rooms
@@ -171,6 +179,37 @@ impl AppService {
         Ok(jr.room_id == *room_id)
     }
 
+    pub async fn reject_invite(
+        &self,
+        room_id: &OwnedRoomId,
+        reason: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut req = leave_room::v3::Request::new(room_id.clone());
+        req.reason = Some(reason.to_string());
+
+        self.client.send_request(req).await?;
+
+        Ok(())
+    }
+
+    /// The content of a room's `m.room.power_levels` event.
+    pub async fn get_power_levels(
+        &self,
+        room_id: &OwnedRoomId,
+    ) -> Result<serde_json::Value, anyhow::Error> {
+        let mut req = get_state_event_for_key::v3::Request::new(
+            room_id.clone(),
+            StateEventType::RoomPowerLevels,
+            String::new(),
+        );
+
+        req.format = get_state_event_for_key::v3::StateEventFormat::Content;
+
+        let pl = self.client.send_request(req).await?;
+
+        Ok(serde_json::from_str(pl.into_content().json().get())?)
+    }
+
     pub async fn has_joined_room(&self, room_id: &OwnedRoomId) -> Result<bool, anyhow::Error> {
 
         let mut req = get_state_event_for_key::v3::Request::new(
@@ -742,6 +781,7 @@ impl AppService {
     pub async fn get_room_messages(
         &self,
         room_id: OwnedRoomId,
+        from: Option<String>,
     ) -> Result<get_message_events::v3::Response, anyhow::Error> {
         let dir = Direction::Backward;
 
@@ -751,6 +791,7 @@ impl AppService {
             ruma::UInt::try_from(100).map_err(|_| anyhow::anyhow!("Invalid limit value"))?;
 
         req.limit = limit;
+        req.from = from;
 
         let response = self.client.send_request(req).await?;
 
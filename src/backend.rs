@@ -0,0 +1,404 @@
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+
+use redis::AsyncCommands;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+
+use tokio::sync::{OnceCell, Semaphore, SemaphorePermit};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cache::CacheError;
+use crate::config::Redis;
+use crate::utils::glob_match;
+
+/// Storage operations used by [`crate::cache::Cache`]. Values are opaque
+/// bytes, serialization happens in `Cache`.
+#[async_trait]
+pub trait CacheBackend: Send + Sync + std::fmt::Debug {
+    async fn set_ex(&self, key: &str, value: Vec<u8>, ttl: u64) -> Result<(), CacheError>;
+
+    /// Sets the key only if it doesn't exist yet, returning whether it was
+    /// set.
+    async fn set_nx_ex(&self, key: &str, value: Vec<u8>, ttl: u64) -> Result<bool, CacheError>;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError>;
+
+    async fn exists(&self, key: &str) -> Result<bool, CacheError>;
+
+    /// Remaining time to live in seconds, following redis conventions: `-2`
+    /// if the key doesn't exist and `-1` if it has no expiry.
+    async fn ttl(&self, key: &str) -> Result<i64, CacheError>;
+
+    async fn del(&self, key: &str) -> Result<(), CacheError>;
+
+    /// Deletes the key only if it holds `value`, as one atomic step. Returns
+    /// whether it was deleted.
+    async fn del_if_eq(&self, key: &str, value: &[u8]) -> Result<bool, CacheError>;
+
+    /// Lists keys matching a redis style glob pattern.
+    async fn keys(&self, pattern: &str) -> Result<Vec<String>, CacheError>;
+}
+
+static DEL_IF_EQ_SCRIPT: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"if redis.call('GET', KEYS[1]) == ARGV[1] then return redis.call('DEL', KEYS[1]) else return 0 end",
+    )
+});
+
+/// Redis backed storage sharing one auto-reconnecting connection manager.
+/// The connection is established lazily, so the appservice starts (and
+/// proxies uncached) while redis is unreachable.
+pub struct RedisBackend {
+    client: redis::Client,
+    timeout: Duration,
+    manager: OnceCell<ConnectionManager>,
+    // caps the number of in-flight commands at `pool_size`
+    permits: Semaphore,
+}
+
+impl std::fmt::Debug for RedisBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisBackend")
+            .field("timeout", &self.timeout)
+            .field("connected", &self.manager.initialized())
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisBackend {
+    pub fn new(config: &Redis) -> Result<Self, CacheError> {
+        let client = redis::Client::open(format!("redis://{}", config.url))?;
+
+        Ok(Self {
+            client,
+            timeout: Duration::from_secs(config.timeout_secs),
+            manager: OnceCell::new(),
+            permits: Semaphore::new(config.pool_size.max(1) as usize),
+        })
+    }
+
+    async fn connection(&self) -> Result<(SemaphorePermit<'_>, ConnectionManager), CacheError> {
+        let permit = tokio::time::timeout(self.timeout, self.permits.acquire())
+            .await
+            .map_err(|_| CacheError::Backend("Timed out waiting for a redis connection".into()))?
+            .map_err(|e| CacheError::Backend(e.to_string()))?;
+
+        // a failed connect isn't stored, the next call tries again
+        let manager = self
+            .manager
+            .get_or_try_init(|| async {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(self.timeout)
+                    .set_response_timeout(self.timeout)
+                    .set_number_of_retries(1);
+
+                let manager = self
+                    .client
+                    .get_connection_manager_with_config(config)
+                    .await?;
+                tracing::info!("Connected to redis");
+
+                Ok::<_, CacheError>(manager)
+            })
+            .await?;
+
+        Ok((permit, manager.clone()))
+    }
+}
+
+#[async_trait]
+impl CacheBackend for RedisBackend {
+    async fn set_ex(&self, key: &str, value: Vec<u8>, ttl: u64) -> Result<(), CacheError> {
+        let (_permit, mut conn) = self.connection().await?;
+        let _: () = conn.set_ex(key, value, ttl).await?;
+        Ok(())
+    }
+
+    async fn set_nx_ex(&self, key: &str, value: Vec<u8>, ttl: u64) -> Result<bool, CacheError> {
+        let (_permit, mut conn) = self.connection().await?;
+        let set: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query_async(&mut conn)
+            .await?;
+        Ok(set.is_some())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        let (_permit, mut conn) = self.connection().await?;
+        Ok(conn.get(key).await?)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, CacheError> {
+        let (_permit, mut conn) = self.connection().await?;
+        Ok(conn.exists(key).await?)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<i64, CacheError> {
+        let (_permit, mut conn) = self.connection().await?;
+        Ok(conn.ttl(key).await?)
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CacheError> {
+        let (_permit, mut conn) = self.connection().await?;
+        let _: () = conn.del(key).await?;
+        Ok(())
+    }
+
+    async fn del_if_eq(&self, key: &str, value: &[u8]) -> Result<bool, CacheError> {
+        let (_permit, mut conn) = self.connection().await?;
+        let deleted: i64 = DEL_IF_EQ_SCRIPT
+            .key(key)
+            .arg(value)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(deleted == 1)
+    }
+
+    async fn keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        let (_permit, mut conn) = self.connection().await?;
+
+        // SCAN so large keyspaces don't block the server
+        let mut iter: redis::AsyncIter<String> = conn.scan_match(pattern).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key?);
+        }
+        Ok(keys)
+    }
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    value: Vec<u8>,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct MemoryStore {
+    entries: HashMap<String, MemoryEntry>,
+    // last_used tick -> key, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl MemoryStore {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.recency.insert(tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<MemoryEntry> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        Some(entry)
+    }
+
+    /// Returns the live entry for a key, dropping it if it has expired.
+    fn live(&mut self, key: &str, now: Instant) -> Option<&MemoryEntry> {
+        if self.entries.get(key)?.expires_at <= now {
+            self.remove(key);
+            return None;
+        }
+        self.entries.get(key)
+    }
+}
+
+/// In-process LRU cache with per-key expiry, for deployments without redis.
+#[derive(Debug)]
+pub struct MemoryBackend {
+    max_entries: usize,
+    store: Mutex<MemoryStore>,
+}
+
+impl MemoryBackend {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries: max_entries.max(1),
+            store: Mutex::new(MemoryStore::default()),
+        }
+    }
+
+    fn store(&self) -> Result<std::sync::MutexGuard<'_, MemoryStore>, CacheError> {
+        self.store
+            .lock()
+            .map_err(|_| CacheError::Backend("Failed to acquire lock on memory cache".to_string()))
+    }
+
+    fn insert(&self, store: &mut MemoryStore, key: &str, value: Vec<u8>, ttl: u64, now: Instant) {
+        store.remove(key);
+
+        // make room by dropping expired entries first, then the least
+        // recently used ones
+        if store.entries.len() >= self.max_entries {
+            let expired: Vec<String> = store
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.expires_at <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in expired {
+                store.remove(&key);
+            }
+        }
+
+        while store.entries.len() >= self.max_entries {
+            let Some((_, oldest)) = store.recency.pop_first() else {
+                break;
+            };
+            store.entries.remove(&oldest);
+        }
+
+        store.entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                value,
+                expires_at: now + Duration::from_secs(ttl),
+                last_used: 0,
+            },
+        );
+        store.touch(key);
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MemoryBackend {
+    async fn set_ex(&self, key: &str, value: Vec<u8>, ttl: u64) -> Result<(), CacheError> {
+        let mut store = self.store()?;
+        self.insert(&mut store, key, value, ttl, Instant::now());
+        Ok(())
+    }
+
+    async fn set_nx_ex(&self, key: &str, value: Vec<u8>, ttl: u64) -> Result<bool, CacheError> {
+        let mut store = self.store()?;
+        let now = Instant::now();
+
+        if store.live(key, now).is_some() {
+            return Ok(false);
+        }
+        self.insert(&mut store, key, value, ttl, now);
+        Ok(true)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        let mut store = self.store()?;
+
+        let value = store
+            .live(key, Instant::now())
+            .map(|entry| entry.value.clone());
+        if value.is_some() {
+            store.touch(key);
+        }
+
+        Ok(value)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, CacheError> {
+        let mut store = self.store()?;
+        Ok(store.live(key, Instant::now()).is_some())
+    }
+
+    async fn ttl(&self, key: &str) -> Result<i64, CacheError> {
+        let mut store = self.store()?;
+        let now = Instant::now();
+
+        Ok(match store.live(key, now) {
+            Some(entry) => entry.expires_at.duration_since(now).as_secs() as i64,
+            None => -2,
+        })
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CacheError> {
+        self.store()?.remove(key);
+        Ok(())
+    }
+
+    async fn del_if_eq(&self, key: &str, value: &[u8]) -> Result<bool, CacheError> {
+        let mut store = self.store()?;
+
+        if store
+            .live(key, Instant::now())
+            .is_none_or(|entry| entry.value != value)
+        {
+            return Ok(false);
+        }
+        store.remove(key);
+        Ok(true)
+    }
+
+    async fn keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        let store = self.store()?;
+        let now = Instant::now();
+
+        Ok(store
+            .entries
+            .iter()
+            .filter(|(key, entry)| entry.expires_at > now && glob_match(pattern, key, true))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_backend_evicts_least_recently_used() {
+        let backend = MemoryBackend::new(2);
+
+        backend.set_ex("a", b"1".to_vec(), 60).await.unwrap();
+        backend.set_ex("b", b"2".to_vec(), 60).await.unwrap();
+
+        // reading "a" makes "b" the least recently used
+        assert_eq!(backend.get("a").await.unwrap(), Some(b"1".to_vec()));
+
+        backend.set_ex("c", b"3".to_vec(), 60).await.unwrap();
+
+        assert!(backend.exists("a").await.unwrap());
+        assert!(!backend.exists("b").await.unwrap());
+        assert!(backend.exists("c").await.unwrap());
+        assert_eq!(backend.ttl("b").await.unwrap(), -2);
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend_expires_entries() {
+        let backend = MemoryBackend::new(10);
+
+        backend.set_ex("a", b"1".to_vec(), 0).await.unwrap();
+        backend.set_ex("b", b"2".to_vec(), 60).await.unwrap();
+
+        assert_eq!(backend.get("a").await.unwrap(), None);
+        assert!(backend.ttl("b").await.unwrap() > 0);
+        assert_eq!(backend.keys("*").await.unwrap(), vec!["b".to_string()]);
+
+        backend.del("b").await.unwrap();
+        assert!(!backend.exists("b").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend_del_if_eq() {
+        let backend = MemoryBackend::new(10);
+
+        backend.set_ex("lock", b"mine".to_vec(), 60).await.unwrap();
+        assert!(!backend.del_if_eq("lock", b"theirs").await.unwrap());
+        assert!(backend.exists("lock").await.unwrap());
+
+        assert!(backend.del_if_eq("lock", b"mine").await.unwrap());
+        assert!(!backend.exists("lock").await.unwrap());
+        assert!(!backend.del_if_eq("lock", b"mine").await.unwrap());
+    }
+}
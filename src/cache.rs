@@ -1,6 +1,12 @@
-use crate::config::Config;
-use redis::{AsyncCommands, RedisError};
+use crate::backend::{CacheBackend, MemoryBackend, RedisBackend};
+use crate::config::{CacheBackendKind, Config};
+use redis::RedisError;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crate::appservice::RoomSummary;
 use crate::rooms::PublicRoom;
@@ -37,79 +43,414 @@ impl CacheKey for (&str, String) {
     }
 }
 
+/// Key prefixes of everything the appservice stores in the cache, used to
+/// group keys for stats and admin cache management. The room lists are
+/// configuration rather than cached data, so they're left out to keep them
+/// from being flushed.
+pub const CACHE_CATEGORIES: [&str; 12] = [
+    "proxy_request",
+    "proxy_post_request",
+    "public_rooms",
+    "public_room_index",
+    "public_spaces",
+    "space_summary",
+    "space_rooms",
+    "public_policy",
+    "appservice:joined",
+    "appservice:join_reason",
+    "appservice:txn",
+    "cache_lock",
+];
+
+pub fn key_category(key: &str) -> &'static str {
+    CACHE_CATEGORIES
+        .iter()
+        .filter(|category| key.starts_with(*category))
+        .max_by_key(|category| category.len())
+        .copied()
+        .unwrap_or("other")
+}
+
+/// Cached data derived from room state, which goes out of date when the
+/// state changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateCache {
+    PublicRooms,
+    PublicSpaces,
+    SpaceSummaries,
+    SpaceRooms,
+    /// Proxied `/state` responses for the room the event was sent in.
+    RoomState,
+}
+
+/// Maps a state event type to the cached data it affects. Every state event
+/// apart from memberships changes the room's own `/state` responses.
+pub fn affected_caches(event_type: &str) -> &'static [StateCache] {
+    use StateCache::*;
+
+    match event_type {
+        // shown in room directory and space listings
+        "m.room.name"
+        | "m.room.topic"
+        | "m.room.avatar"
+        | "m.room.canonical_alias"
+        | "m.room.join_rules"
+        | "m.room.guest_access"
+        | "m.room.history_visibility" => &[
+            PublicRooms,
+            PublicSpaces,
+            SpaceSummaries,
+            SpaceRooms,
+            RoomState,
+        ],
+        "m.space.child" | "m.space.parent" => {
+            &[PublicSpaces, SpaceSummaries, SpaceRooms, RoomState]
+        }
+        "commune.public.room" => &[PublicRooms, RoomState],
+        // membership churns too much to invalidate on, `/state` responses
+        // catch up when they expire
+        "m.room.member" => &[],
+        _ => &[RoomState],
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheStats {
+    counters: Arc<Mutex<HashMap<&'static str, CacheCounters>>>,
+}
+
+impl CacheStats {
+    pub fn record(&self, key: &str, hit: bool) {
+        let category = key_category(key);
+
+        if hit {
+            metrics::counter!("cache_hits", "category" => category).increment(1);
+        } else {
+            metrics::counter!("cache_misses", "category" => category).increment(1);
+        }
+
+        if let Ok(mut counters) = self.counters.lock() {
+            let counter = counters.entry(category).or_default();
+            if hit {
+                counter.hits += 1;
+            } else {
+                counter.misses += 1;
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<&'static str, CacheCounters> {
+        self.counters
+            .lock()
+            .map(|counters| counters.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// A room ID as it appears percent-encoded in proxied URLs.
+pub fn encode_room_id(room_id: &str) -> String {
+    room_id.replace('!', "%21").replace(':', "%3A")
+}
+
+/// Escapes the glob characters redis `SCAN MATCH` understands.
+fn escape_pattern(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] RedisError),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Cache backend error: {0}")]
+    Backend(String),
+    #[error("Key not found")]
+    NotFound,
+    #[error("TTL remaining is greater than threshold, not caching")]
+    AboveThreshold,
+    #[error("{0}")]
+    Fetch(String),
+}
+
+/// Cached value with a soft expiry. Entries are stored for `ttl + stale_ttl`
+/// seconds; once `fresh_until` passes they can still be served while a
+/// refresh runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry<T> {
+    pub data: T,
+    pub fresh_until: u64,
+}
+
+impl<T> CacheEntry<T> {
+    pub fn new(data: T, ttl: u64) -> Self {
+        Self {
+            data,
+            fresh_until: unix_now() + ttl,
+        }
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        unix_now() < self.fresh_until
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// How often a replica waiting on another's fetch checks for the result.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Per-key locks for fetches currently in flight in this process.
+#[derive(Debug, Clone, Default)]
+struct InFlight {
+    locks: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl InFlight {
+    fn lock_for(&self, key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        locks.entry(key.to_string()).or_default().clone()
+    }
+
+    fn release(&self, key: &str, lock: Arc<tokio::sync::Mutex<()>>) {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        // only the map and this caller hold it, nobody else is waiting
+        if Arc::strong_count(&lock) <= 2 {
+            locks.remove(key);
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Cache {
-    pub client: redis::Client,
+    pub backend: Arc<dyn CacheBackend>,
+    pub stats: CacheStats,
+    inflight: InFlight,
+    distributed_lock: bool,
+    lock_timeout: Duration,
 }
 
 impl Cache {
     pub async fn new(config: &Config) -> Result<Self, anyhow::Error> {
-        let url = format!("redis://{}", config.redis.url);
-        let client = redis::Client::open(url)?;
+        let backend: Arc<dyn CacheBackend> = match config.cache.backend {
+            CacheBackendKind::Redis => Arc::new(RedisBackend::new(&config.redis)?),
+            CacheBackendKind::Memory => {
+                tracing::info!(
+                    "Using in-memory cache backend ({} entries)",
+                    config.cache.max_entries
+                );
+                Arc::new(MemoryBackend::new(config.cache.max_entries))
+            }
+        };
 
-        Ok(Self { client })
+        Ok(Self {
+            backend,
+            stats: CacheStats::default(),
+            inflight: InFlight::default(),
+            distributed_lock: config.cache.distributed_lock,
+            lock_timeout: Duration::from_secs(config.cache.lock_timeout_secs),
+        })
     }
 
-    pub async fn cache_data<T>(&self, key: &str, data: &T, ttl: u64) -> Result<(), RedisError>
+    pub async fn cache_data<T>(&self, key: &str, data: &T, ttl: u64) -> Result<(), CacheError>
     where
         T: Cacheable,
     {
-        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
-
-        let serialized = serde_json::to_string(data).map_err(|e| {
-            RedisError::from((
-                redis::ErrorKind::IoError,
-                "Serialization error",
-                e.to_string(),
-            ))
-        })?;
+        let serialized = serde_json::to_vec(data)?;
 
-        let _: () = conn.set_ex(key, serialized, ttl).await?;
-        Ok(())
+        self.backend.set_ex(key, serialized, ttl).await
     }
 
-    pub async fn get_cached_data<T>(&self, key: &str) -> Result<Option<T>, RedisError>
+    pub async fn get_cached_data<T>(&self, key: &str) -> Result<Option<T>, CacheError>
     where
         T: Cacheable,
     {
-        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        let data = self.backend.get(key).await?;
+        self.stats.record(key, data.is_some());
 
-        let exists: bool = conn.exists(key).await?;
-        if !exists {
-            return Ok(None);
+        match data {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
         }
-
-        let data: String = conn.get(key).await?;
-        let value = serde_json::from_str(&data).map_err(|e| {
-            RedisError::from((
-                redis::ErrorKind::IoError,
-                "Deserialization error",
-                e.to_string(),
-            ))
-        })?;
-        Ok(Some(value))
     }
 
+    /// Returns the cached value for `key`, or fetches and caches it. Concurrent
+    /// misses for the same key are coalesced so only one fetch runs, the
+    /// other callers wait and read its result from the cache. With
+    /// `distributed_lock` enabled this also applies across replicas.
     pub async fn cache_or_fetch<T, F, Fut>(
         &self,
         key: &str,
         ttl: u64,
         fetch_fn: F,
-    ) -> Result<T, RedisError>
+    ) -> Result<T, CacheError>
+    where
+        T: Cacheable,
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<T, CacheError>>,
+    {
+        self.cache_or_fetch_with_ttl(key, || async { Ok((fetch_fn().await?, ttl)) })
+            .await
+    }
+
+    /// Like [`Cache::cache_or_fetch`], but the fetch decides how long its
+    /// result is cached. A TTL of `0` returns the result without caching it.
+    pub async fn cache_or_fetch_with_ttl<T, F, Fut>(
+        &self,
+        key: &str,
+        fetch_fn: F,
+    ) -> Result<T, CacheError>
     where
         T: Cacheable,
         F: FnOnce() -> Fut,
-        Fut: std::future::Future<Output = Result<T, RedisError>>,
+        Fut: std::future::Future<Output = Result<(T, u64), CacheError>>,
     {
-        if let Some(cached) = self.get_cached_data::<T>(key).await? {
+        if let Some(cached) = self.try_get::<T>(key).await {
             return Ok(cached);
         }
 
-        let data = fetch_fn().await?;
+        let lock = self.inflight.lock_for(key);
+        let result = {
+            let _guard = lock.lock().await;
+            self.fetch_once(key, fetch_fn).await
+        };
+        self.inflight.release(key, lock);
 
-        let _ = self.cache_data(key, &data, ttl).await;
+        result
+    }
 
-        Ok(data)
+    /// Fetches and stores a value, unless a fetch for the key is already in
+    /// flight in this process. Used to revalidate stale entries in the
+    /// background. As with [`Cache::cache_or_fetch_with_ttl`] a TTL of `0`
+    /// leaves the current entry in place.
+    pub async fn refresh_entry<T, F, Fut>(&self, key: &str, fetch_fn: F) -> Result<(), CacheError>
+    where
+        T: Cacheable,
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<(T, u64), CacheError>>,
+    {
+        let lock = self.inflight.lock_for(key);
+        let result = match lock.try_lock() {
+            Ok(_guard) => match fetch_fn().await {
+                Ok((_, 0)) => Ok(()),
+                Ok((data, ttl)) => self.cache_data(key, &data, ttl).await,
+                Err(e) => Err(e),
+            },
+            Err(_) => Ok(()),
+        };
+        self.inflight.release(key, lock);
+
+        result
+    }
+
+    /// An unavailable cache is treated as a miss, so callers still get fresh
+    /// data instead of an error.
+    async fn try_get<T>(&self, key: &str) -> Option<T>
+    where
+        T: Cacheable,
+    {
+        match self.get_cached_data::<T>(key).await {
+            Ok(cached) => cached,
+            Err(e) => {
+                tracing::warn!("Cache read failed for key '{}': {}", key, e);
+                None
+            }
+        }
+    }
+
+    async fn fetch_once<T, F, Fut>(&self, key: &str, fetch_fn: F) -> Result<T, CacheError>
+    where
+        T: Cacheable,
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<(T, u64), CacheError>>,
+    {
+        // whoever held the lock before us may have filled the cache already
+        if let Some(cached) = self.try_get::<T>(key).await {
+            return Ok(cached);
+        }
+
+        let lock_key = ("cache_lock", key).cache_key();
+        let token = uuid::Uuid::new_v4().to_string();
+
+        let locked = self.distributed_lock && self.acquire_lock(&lock_key, &token, key).await;
+
+        if self.distributed_lock
+            && !locked
+            && let Some(cached) = self.try_get::<T>(key).await
+        {
+            return Ok(cached);
+        }
+
+        let result = fetch_fn().await;
+
+        if let Ok((data, ttl)) = &result
+            && *ttl > 0
+            && let Err(e) = self.cache_data(key, data, *ttl).await
+        {
+            tracing::warn!("Cache write failed for key '{}': {}", key, e);
+        }
+
+        if locked {
+            self.release_lock(&lock_key, &token).await;
+        }
+
+        result.map(|(data, _)| data)
+    }
+
+    /// Takes the cross-replica lock for a key. If another replica holds it,
+    /// waits until the cached value shows up or the lock times out. Returns
+    /// whether this caller holds the lock.
+    async fn acquire_lock(&self, lock_key: &str, token: &str, key: &str) -> bool {
+        let lock_ttl = self.lock_timeout.as_secs().max(1);
+        let deadline = tokio::time::Instant::now() + self.lock_timeout;
+
+        loop {
+            match self
+                .backend
+                .set_nx_ex(lock_key, token.as_bytes().to_vec(), lock_ttl)
+                .await
+            {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::warn!("Failed to take cache lock for key '{}': {}", key, e);
+                    return false;
+                }
+            }
+
+            if tokio::time::Instant::now() >= deadline
+                || matches!(self.backend.exists(key).await, Ok(true))
+            {
+                return false;
+            }
+
+            tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn release_lock(&self, lock_key: &str, token: &str) {
+        // don't release a lock that expired and was taken by someone else
+        if let Err(e) = self.backend.del_if_eq(lock_key, token.as_bytes()).await {
+            tracing::warn!("Failed to release cache lock '{}': {}", lock_key, e);
+        }
     }
 
     pub async fn cache_with_ttl_threshold<T>(
@@ -118,13 +459,11 @@ impl Cache {
         data: T,
         new_ttl: u64,
         ttl_threshold: u64,
-    ) -> Result<(), RedisError>
+    ) -> Result<(), CacheError>
     where
         T: Cacheable,
     {
-        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
-
-        let ttl_remaining: i64 = conn.ttl(key).await?;
+        let ttl_remaining = self.backend.ttl(key).await?;
 
         tracing::info!("TTL remaining for key '{}': {}", key, ttl_remaining);
 
@@ -142,10 +481,7 @@ impl Cache {
         );
 
         if !should_cache {
-            return Err(RedisError::from((
-                redis::ErrorKind::ResponseError,
-                "TTL remaining is greater than threshold, not caching",
-            )));
+            return Err(CacheError::AboveThreshold);
         }
 
         self.cache_data(key, &data, new_ttl).await?;
@@ -153,7 +489,7 @@ impl Cache {
         Ok(())
     }
 
-    pub async fn cache_with_key<K, T>(&self, key: K, data: &T, ttl: u64) -> Result<(), RedisError>
+    pub async fn cache_with_key<K, T>(&self, key: K, data: &T, ttl: u64) -> Result<(), CacheError>
     where
         K: CacheKey,
         T: Cacheable,
@@ -161,7 +497,7 @@ impl Cache {
         self.cache_data(&key.cache_key(), data, ttl).await
     }
 
-    pub async fn get_with_key<K, T>(&self, key: K) -> Result<Option<T>, RedisError>
+    pub async fn get_with_key<K, T>(&self, key: K) -> Result<Option<T>, CacheError>
     where
         K: CacheKey,
         T: Cacheable,
@@ -169,44 +505,42 @@ impl Cache {
         self.get_cached_data(&key.cache_key()).await
     }
 
-    pub async fn delete_cached_data(&self, key: &str) -> Result<(), RedisError> {
-        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
-        let _: () = conn.del(key).await?;
-        Ok(())
+    pub async fn delete_cached_data(&self, key: &str) -> Result<(), CacheError> {
+        self.backend.del(key).await
     }
 
-    pub async fn cache_rooms(&self, rooms: &Vec<PublicRoom>, ttl: u64) -> Result<(), RedisError> {
+    pub async fn cache_rooms(&self, rooms: &Vec<PublicRoom>, ttl: u64) -> Result<(), CacheError> {
         self.cache_data("public_rooms", rooms, ttl).await
     }
 
-    pub async fn get_cached_rooms(&self) -> Result<Vec<PublicRoom>, RedisError> {
+    pub async fn get_cached_rooms(&self) -> Result<Vec<PublicRoom>, CacheError> {
         self.get_cached_data("public_rooms")
             .await?
-            .ok_or_else(|| RedisError::from((redis::ErrorKind::ResponseError, "Key not found")))
+            .ok_or(CacheError::NotFound)
     }
 
     pub async fn get_cached_room_state(
         &self,
         room_id: &str,
-    ) -> Result<Vec<PublicRoom>, RedisError> {
+    ) -> Result<Vec<PublicRoom>, CacheError> {
         let key = format!("room_state:{room_id}");
         self.get_cached_data(&key)
             .await?
-            .ok_or_else(|| RedisError::from((redis::ErrorKind::ResponseError, "Key not found")))
+            .ok_or(CacheError::NotFound)
     }
 
     pub async fn cache_public_spaces(
         &self,
         rooms: &Vec<RoomSummary>,
         ttl: u64,
-    ) -> Result<(), RedisError> {
+    ) -> Result<(), CacheError> {
         self.cache_data("public_spaces", rooms, ttl).await
     }
 
-    pub async fn get_cached_public_spaces(&self) -> Result<Vec<RoomSummary>, RedisError> {
+    pub async fn get_cached_public_spaces(&self) -> Result<Vec<RoomSummary>, CacheError> {
         self.get_cached_data("public_spaces")
             .await?
-            .ok_or_else(|| RedisError::from((redis::ErrorKind::ResponseError, "Key not found")))
+            .ok_or(CacheError::NotFound)
     }
 
     pub async fn cache_room_state(
@@ -214,7 +548,7 @@ impl Cache {
         room_id: &str,
         state: &Vec<PublicRoom>,
         ttl: u64,
-    ) -> Result<(), RedisError> {
+    ) -> Result<(), CacheError> {
         let key = format!("room_state:{room_id}");
         self.cache_data(&key, state, ttl).await
     }
@@ -224,26 +558,18 @@ impl Cache {
         key: &str,
         data: &[u8],
         ttl: u64,
-    ) -> Result<(), RedisError> {
-        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
-        let _: () = conn.set_ex(key, data, ttl).await?;
-        Ok(())
+    ) -> Result<(), CacheError> {
+        self.backend.set_ex(key, data.to_vec(), ttl).await
     }
 
-    pub async fn get_cached_proxy_response(&self, key: &str) -> Result<Vec<u8>, RedisError> {
-        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
-
-        if !conn.exists(key).await? {
-            return Err(RedisError::from((
-                redis::ErrorKind::ResponseError,
-                "Key not found",
-            )));
-        }
+    pub async fn get_cached_proxy_response(&self, key: &str) -> Result<Vec<u8>, CacheError> {
+        let data = self.backend.get(key).await?;
+        self.stats.record(key, data.is_some());
 
-        conn.get(key).await
+        data.ok_or(CacheError::NotFound)
     }
 
-    pub async fn cache_multiple<T>(&self, items: Vec<(&str, &T, u64)>) -> Result<(), RedisError>
+    pub async fn cache_multiple<T>(&self, items: Vec<(&str, &T, u64)>) -> Result<(), CacheError>
     where
         T: Cacheable,
     {
@@ -253,10 +579,177 @@ impl Cache {
         Ok(())
     }
 
-    pub async fn delete_multiple(&self, keys: &[&str]) -> Result<(), RedisError> {
+    pub async fn delete_multiple(&self, keys: &[&str]) -> Result<(), CacheError> {
         for key in keys {
             self.delete_cached_data(key).await?;
         }
         Ok(())
     }
+
+    /// Lists keys matching a redis style glob pattern.
+    pub async fn keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        self.backend.keys(pattern).await
+    }
+
+    pub async fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, CacheError> {
+        self.keys(&format!("{}*", escape_pattern(prefix))).await
+    }
+
+    pub async fn keys_containing(&self, needle: &str) -> Result<Vec<String>, CacheError> {
+        self.keys(&format!("*{}*", escape_pattern(needle))).await
+    }
+
+    pub async fn delete_keys(&self, keys: &[String]) -> Result<usize, CacheError> {
+        for key in keys {
+            self.delete_cached_data(key).await?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Deletes the cached state of a room and its proxied `/state` responses,
+    /// with the room ID both raw and percent-encoded in the URL.
+    pub async fn delete_room_state(
+        &self,
+        homeserver: &str,
+        room_id: &str,
+    ) -> Result<usize, CacheError> {
+        let mut keys = vec![format!("room_state:{room_id}")];
+
+        for room_id in [room_id.to_string(), encode_room_id(room_id)] {
+            let prefix = (
+                "proxy_request",
+                format!("{homeserver}/_matrix/client/v3/rooms/{room_id}/state"),
+            )
+                .cache_key();
+            keys.extend(self.keys_with_prefix(&prefix).await?);
+        }
+
+        self.delete_keys(&keys).await
+    }
+
+    pub async fn delete_by_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        let keys = self.keys_with_prefix(prefix).await?;
+        self.delete_keys(&keys).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_category() {
+        assert_eq!(
+            key_category("proxy_request:http://localhost/_matrix"),
+            "proxy_request"
+        );
+        assert_eq!(
+            key_category("proxy_post_request:http://localhost/search:abc"),
+            "proxy_post_request"
+        );
+        assert_eq!(
+            key_category("appservice:join_reason:!room:test.local"),
+            "appservice:join_reason"
+        );
+        assert_eq!(key_category("public_rooms"), "public_rooms");
+        assert_eq!(key_category("unknown"), "other");
+    }
+
+    #[tokio::test]
+    async fn test_cache_or_fetch_coalesces_misses() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let cache = Cache {
+            backend: Arc::new(MemoryBackend::new(10)),
+            stats: CacheStats::default(),
+            inflight: InFlight::default(),
+            distributed_lock: true,
+            lock_timeout: Duration::from_secs(5),
+        };
+        let fetches = Arc::new(AtomicUsize::new(0));
+
+        let requests = (0..10).map(|_| {
+            let cache = cache.clone();
+            let fetches = fetches.clone();
+            async move {
+                cache
+                    .cache_or_fetch("public_rooms", 60, || async {
+                        fetches.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok(vec!["room".to_string()])
+                    })
+                    .await
+            }
+        });
+
+        for result in futures::future::join_all(requests).await {
+            assert_eq!(result.unwrap(), vec!["room".to_string()]);
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(cache.inflight.locks.lock().unwrap().is_empty());
+        assert!(
+            !cache
+                .backend
+                .exists("cache_lock:public_rooms")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_affected_caches() {
+        assert!(affected_caches("m.room.name").contains(&StateCache::PublicRooms));
+        assert!(affected_caches("m.space.child").contains(&StateCache::SpaceRooms));
+        assert!(!affected_caches("m.space.child").contains(&StateCache::PublicRooms));
+        assert!(affected_caches("m.room.member").is_empty());
+        assert_eq!(
+            affected_caches("m.room.power_levels"),
+            &[StateCache::RoomState]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_room_state() {
+        let cache = Cache {
+            backend: Arc::new(MemoryBackend::new(10)),
+            stats: CacheStats::default(),
+            inflight: InFlight::default(),
+            distributed_lock: false,
+            lock_timeout: Duration::from_secs(5),
+        };
+
+        let keys = [
+            "proxy_request:http://hs/_matrix/client/v3/rooms/!a:test.local/state",
+            "proxy_request:http://hs/_matrix/client/v3/rooms/%21a%3Atest.local/state",
+            "proxy_request:http://hs/_matrix/client/v3/rooms/!a:test.local/messages",
+            "proxy_request:http://hs/_matrix/client/v3/rooms/!b:test.local/state",
+            "room_state:!a:test.local",
+        ];
+        for key in keys {
+            cache.cache_data(key, &1, 60).await.unwrap();
+        }
+
+        assert_eq!(
+            cache
+                .delete_room_state("http://hs", "!a:test.local")
+                .await
+                .unwrap(),
+            3
+        );
+        assert!(cache.backend.exists(keys[2]).await.unwrap());
+        assert!(cache.backend.exists(keys[3]).await.unwrap());
+        assert!(!cache.backend.exists(keys[4]).await.unwrap());
+    }
+
+    #[test]
+    fn test_cache_entry_freshness() {
+        assert!(CacheEntry::new((), 60).is_fresh());
+        assert!(!CacheEntry::new((), 0).is_fresh());
+    }
+
+    #[test]
+    fn test_escape_pattern() {
+        assert_eq!(escape_pattern("!room:test.local"), "!room:test.local");
+        assert_eq!(escape_pattern("a*b?[c]"), "a\\*b\\?\\[c\\]");
+    }
 }
@@ -23,6 +23,14 @@ pub struct Config {
     pub sentry: Option<Sentry>,
     #[serde(default)]
     pub metrics: Metrics,
+    #[serde(default)]
+    pub admin: Admin,
+    #[serde(default)]
+    pub media: Media,
+    #[serde(default)]
+    pub moderation: Moderation,
+    #[serde(default)]
+    pub state_events: StateEvents,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,6 +38,10 @@ pub struct Server {
     #[serde(default = "default_port")]
     pub port: u16,
     pub allow_origin: Option<Vec<String>>,
+    #[serde(default = "default_max_request_body_mb")]
+    pub max_request_body_mb: u64,
+    #[serde(default = "default_max_response_body_mb")]
+    pub max_response_body_mb: u64,
 }
 
 impl Default for Server {
@@ -37,10 +49,22 @@ impl Default for Server {
         Self {
             port: default_port(),
             allow_origin: None,
+            max_request_body_mb: default_max_request_body_mb(),
+            max_response_body_mb: default_max_response_body_mb(),
         }
     }
 }
 
+impl Server {
+    pub fn max_request_body(&self) -> usize {
+        (self.max_request_body_mb * 1024 * 1024) as usize
+    }
+
+    pub fn max_response_body(&self) -> u64 {
+        self.max_response_body_mb * 1024 * 1024
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppService {
     pub id: String,
@@ -49,16 +73,27 @@ pub struct AppService {
     pub hs_access_token: String,
     #[serde(default)]
     pub rules: AppServiceRules,
+    #[serde(default = "default_transaction_ttl")]
+    pub transaction_ttl: u64,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct AppServiceRules {
     #[serde(default)]
     pub auto_join: bool,
+    /// Only accept invites sent by users on the local homeserver.
     #[serde(default)]
     pub invite_by_local_user: bool,
+    /// Power level the inviter needs in the room, e.g. `50` for moderators.
+    #[serde(default)]
+    pub min_inviter_power_level: Option<i64>,
+    /// Remote servers whose rooms may be joined and served, as exact names or
+    /// `*.example.org` wildcards. Empty allows only the local server.
     #[serde(default)]
     pub federation_domain_whitelist: Vec<String>,
+    /// Servers that are never joined or served, even when whitelisted.
+    #[serde(default)]
+    pub federation_domain_blocklist: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -90,8 +125,27 @@ impl Default for Redis {
     }
 }
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheBackendKind {
+    #[default]
+    Redis,
+    Memory,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cache {
+    #[serde(default)]
+    pub backend: CacheBackendKind,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    #[serde(default)]
+    pub distributed_lock: bool,
+    #[serde(default = "default_lock_timeout_secs")]
+    pub lock_timeout_secs: u64,
+    /// TTL for cached homeserver 4xx responses, `0` disables caching them.
+    #[serde(default = "default_negative_ttl")]
+    pub negative_ttl: u64,
     #[serde(default)]
     pub joined_rooms: CacheOptions,
     #[serde(default)]
@@ -106,6 +160,30 @@ pub struct Cache {
     pub media: CacheOptions,
     #[serde(default)]
     pub search: CacheOptions,
+    /// Public room policy decisions, which every room route needs, so
+    /// they're cached unless turned off.
+    #[serde(default = "default_policy_cache")]
+    pub policy: CacheOptions,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            backend: CacheBackendKind::default(),
+            max_entries: default_max_entries(),
+            distributed_lock: false,
+            lock_timeout_secs: default_lock_timeout_secs(),
+            negative_ttl: default_negative_ttl(),
+            joined_rooms: CacheOptions::default(),
+            requests: CacheOptions::default(),
+            public_rooms: CacheOptions::default(),
+            room_state: CacheOptions::default(),
+            messages: CacheOptions::default(),
+            media: CacheOptions::default(),
+            search: CacheOptions::default(),
+            policy: default_policy_cache(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -116,6 +194,10 @@ pub struct CacheOptions {
     pub ttl: u64,
     #[serde(default = "default_cache_ttl")]
     pub refresh_ttl: u64,
+    /// How long an entry may be served stale after `ttl` while it's being
+    /// refreshed, or while the homeserver is failing.
+    #[serde(default)]
+    pub stale_ttl: u64,
 }
 
 impl Default for CacheOptions {
@@ -124,16 +206,29 @@ impl Default for CacheOptions {
             enabled: false,
             ttl: default_cache_ttl(),
             refresh_ttl: default_refresh_ttl(),
+            stale_ttl: 0,
         }
     }
 }
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PublicRooms {
     #[serde(default)]
     pub curated: bool,
     #[serde(default)]
     pub include_rooms: Vec<String>,
+    #[serde(default = "default_true")]
+    pub require_public_event: bool,
+}
+
+impl Default for PublicRooms {
+    fn default() -> Self {
+        Self {
+            curated: false,
+            include_rooms: Vec::new(),
+            require_public_event: default_true(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -177,10 +272,117 @@ pub struct Metrics {
     pub port: u16,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Media {
+    #[serde(default = "default_media_directory")]
+    pub directory: std::path::PathBuf,
+    #[serde(default = "default_media_max_size_mb")]
+    pub max_size_mb: u64,
+    #[serde(default = "default_media_max_file_size_mb")]
+    pub max_file_size_mb: u64,
+}
+
+impl Default for Media {
+    fn default() -> Self {
+        Self {
+            directory: default_media_directory(),
+            max_size_mb: default_media_max_size_mb(),
+            max_file_size_mb: default_media_max_file_size_mb(),
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Admin {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub tokens: Vec<AdminToken>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminToken {
+    pub name: Option<String>,
+    /// Hex encoded SHA-256 hash of the bearer token.
+    pub hash: String,
+    #[serde(default = "default_admin_scopes")]
+    pub scopes: Vec<AdminScope>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminScope {
+    Read,
+    Write,
+}
+
+/// State event types served through `/state`, `/initialSync` and `/context`,
+/// as exact types or prefixes ending in `*`. An empty allowlist allows every
+/// type, and the denylist wins over it.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct StateEvents {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl StateEvents {
+    pub fn is_enabled(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    pub fn allows(&self, event_type: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => event_type.starts_with(prefix),
+            None => pattern == event_type,
+        };
+
+        !self.deny.iter().any(matches) && (self.allow.is_empty() || self.allow.iter().any(matches))
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Moderation {
+    /// Policy list rooms, by ID or alias, whose `m.policy.rule.*` bans are
+    /// enforced. The appservice user has to be joined to them.
+    #[serde(default)]
+    pub policy_rooms: Vec<String>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchBackend {
+    /// Proxy `/search` to the homeserver.
+    #[default]
+    Homeserver,
+    /// Answer `/search` from the embedded message index.
+    Local,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Search {
     #[serde(default)]
     pub disabled: bool,
+    #[serde(default)]
+    pub backend: SearchBackend,
+    /// Messages kept in the local index, oldest are dropped first.
+    #[serde(default = "default_search_max_documents")]
+    pub max_documents: usize,
+    /// Messages fetched per room when the local index is built.
+    #[serde(default = "default_search_backfill_limit")]
+    pub backfill_limit: usize,
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Self {
+            disabled: false,
+            backend: SearchBackend::default(),
+            max_documents: default_search_max_documents(),
+            backfill_limit: default_search_backfill_limit(),
+        }
+    }
 }
 
 fn default_port() -> u16 {
@@ -203,6 +405,54 @@ fn default_cache_ttl() -> u64 {
     300
 }
 
+fn default_policy_cache() -> CacheOptions {
+    CacheOptions {
+        enabled: true,
+        ttl: 60,
+        ..CacheOptions::default()
+    }
+}
+
+fn default_max_entries() -> usize {
+    10000
+}
+
+fn default_lock_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_request_body_mb() -> u64 {
+    10
+}
+
+fn default_max_response_body_mb() -> u64 {
+    100
+}
+
+fn default_media_directory() -> std::path::PathBuf {
+    "media-cache".into()
+}
+
+fn default_media_max_size_mb() -> u64 {
+    1024
+}
+
+fn default_media_max_file_size_mb() -> u64 {
+    20
+}
+
+fn default_search_max_documents() -> usize {
+    100_000
+}
+
+fn default_search_backfill_limit() -> usize {
+    500
+}
+
+fn default_negative_ttl() -> u64 {
+    30
+}
+
 fn default_refresh_ttl() -> u64 {
     60
 }
@@ -211,6 +461,18 @@ fn default_spaces_ttl() -> u64 {
     3600
 }
 
+fn default_transaction_ttl() -> u64 {
+    86400
+}
+
+fn default_admin_scopes() -> Vec<AdminScope> {
+    vec![AdminScope::Read, AdminScope::Write]
+}
+
+fn default_true() -> bool {
+    true
+}
+
 impl Config {
     pub fn new(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
         let path = path.as_ref();
@@ -247,5 +509,31 @@ mod tests {
         assert_eq!(config.redis.pool_size, 10);
         assert!(!config.cache.requests.enabled);
         assert!(!config.public_rooms.curated);
+        assert!(config.public_rooms.require_public_event);
+        assert!(!config.admin.enabled);
+        assert_eq!(config.cache.backend, CacheBackendKind::Redis);
+        assert_eq!(config.search.backend, SearchBackend::Homeserver);
+        assert!(!config.state_events.is_enabled());
+        assert!(config.cache.policy.enabled);
+    }
+
+    #[test]
+    fn test_state_events() {
+        let state_events = StateEvents {
+            allow: vec!["m.room.*".to_string(), "m.space.child".to_string()],
+            deny: vec!["m.room.server_acl".to_string()],
+        };
+
+        assert!(state_events.allows("m.room.name"));
+        assert!(state_events.allows("m.space.child"));
+        assert!(!state_events.allows("m.room.server_acl"));
+        assert!(!state_events.allows("im.vector.modular.widgets"));
+
+        let deny_only = StateEvents {
+            allow: Vec::new(),
+            deny: vec!["im.vector.*".to_string()],
+        };
+        assert!(deny_only.allows("m.room.power_levels"));
+        assert!(!deny_only.allows("im.vector.modular.widgets"));
     }
 }
@@ -15,6 +15,8 @@ pub enum AppserviceError {
     HomeserverError(String),
     #[error("Matrix API error: {0}")]
     MatrixError(String),
+    #[error("Invalid parameter: {0}")]
+    InvalidParam(String),
 }
 
 impl IntoResponse for AppserviceError {
@@ -23,6 +25,7 @@ impl IntoResponse for AppserviceError {
             AppserviceError::AppserviceError(_) => (StatusCode::NOT_FOUND, self.to_string()),
             AppserviceError::HomeserverError(_) => (StatusCode::BAD_GATEWAY, self.to_string()),
             AppserviceError::MatrixError(_) => (StatusCode::NOT_FOUND, self.to_string()),
+            AppserviceError::InvalidParam(_) => (StatusCode::BAD_REQUEST, self.to_string()),
         };
 
         (status, Json(json!({ "error": message }))).into_response()
@@ -0,0 +1,211 @@
+use async_trait::async_trait;
+
+use ruma::events::AnyStateEvent;
+use ruma::events::room::{
+    history_visibility::RoomHistoryVisibilityEvent, member::RoomMemberEvent,
+    message::RoomMessageEvent, redaction::RoomRedactionEvent,
+};
+use ruma::events::space::child::SpaceChildEvent;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::api::CommunePublicRoomEvent;
+
+/// Typed view of an event delivered in an appservice transaction.
+#[derive(Debug)]
+pub enum AppserviceEvent {
+    HistoryVisibility(RoomHistoryVisibilityEvent),
+    SpaceChild(SpaceChildEvent),
+    CommunePublicRoom(CommunePublicRoomEvent),
+    Member(RoomMemberEvent),
+    Message(RoomMessageEvent),
+    Redaction(RoomRedactionEvent),
+    State(AnyStateEvent),
+    Other,
+}
+
+#[derive(Debug)]
+pub struct TransactionEvent {
+    pub raw: Value,
+    pub kind: AppserviceEvent,
+}
+
+impl TransactionEvent {
+    pub fn parse(raw: Value) -> Self {
+        let kind = match raw["type"].as_str() {
+            Some("m.room.history_visibility") => serde_json::from_value(raw.clone())
+                .ok()
+                .map(AppserviceEvent::HistoryVisibility),
+            Some("m.space.child") => serde_json::from_value(raw.clone())
+                .ok()
+                .map(AppserviceEvent::SpaceChild),
+            Some("commune.public.room") => serde_json::from_value(raw.clone())
+                .ok()
+                .map(AppserviceEvent::CommunePublicRoom),
+            Some("m.room.member") => serde_json::from_value(raw.clone())
+                .ok()
+                .map(AppserviceEvent::Member),
+            Some("m.room.message") => serde_json::from_value(raw.clone())
+                .ok()
+                .map(AppserviceEvent::Message),
+            Some("m.room.redaction") => serde_json::from_value(raw.clone())
+                .ok()
+                .map(AppserviceEvent::Redaction),
+            _ => None,
+        };
+
+        let kind = kind
+            .or_else(|| {
+                raw.get("state_key")?;
+                serde_json::from_value(raw.clone())
+                    .ok()
+                    .map(AppserviceEvent::State)
+            })
+            .unwrap_or(AppserviceEvent::Other);
+
+        Self { raw, kind }
+    }
+
+    pub fn event_type(&self) -> &str {
+        self.raw["type"].as_str().unwrap_or_default()
+    }
+
+    pub fn event_id(&self) -> Option<&str> {
+        self.raw["event_id"].as_str()
+    }
+
+    pub fn room_id(&self) -> Option<&str> {
+        self.raw["room_id"].as_str()
+    }
+
+    pub fn state_key(&self) -> Option<&str> {
+        self.raw["state_key"].as_str()
+    }
+
+    pub fn is_state(&self) -> bool {
+        self.state_key().is_some()
+    }
+}
+
+#[async_trait]
+pub trait EventHandler: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn handles(&self, state: &AppState, event: &TransactionEvent) -> bool;
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HandlerFailure {
+    pub event_id: Option<String>,
+    pub event_type: String,
+    pub handler: &'static str,
+    pub error: String,
+}
+
+#[derive(Clone, Default)]
+pub struct EventDispatcher {
+    handlers: Vec<Arc<dyn EventHandler>>,
+}
+
+impl EventDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<H>(&mut self, handler: H)
+    where
+        H: EventHandler + 'static,
+    {
+        self.handlers.push(Arc::new(handler));
+    }
+
+    /// Runs every event in a transaction through each handler that applies
+    /// to it, in order. A failing handler doesn't stop the others, the
+    /// failures are collected and returned instead.
+    pub async fn dispatch(&self, state: Arc<AppState>, events: Vec<Value>) -> Vec<HandlerFailure> {
+        let mut failures = Vec::new();
+
+        for raw in events {
+            if cfg!(debug_assertions) {
+                tracing::info!("Event: {:#?}", raw);
+            }
+
+            let event = TransactionEvent::parse(raw);
+
+            for handler in &self.handlers {
+                if !handler.handles(&state, &event) {
+                    continue;
+                }
+
+                if let Err(e) = handler.handle(state.clone(), &event).await {
+                    failures.push(HandlerFailure {
+                        event_id: event.event_id().map(|id| id.to_string()),
+                        event_type: event.event_type().to_string(),
+                        handler: handler.name(),
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(event_type: &str, state_key: Option<&str>, content: Value) -> Value {
+        let mut event = json!({
+            "type": event_type,
+            "content": content,
+            "event_id": "$event:test.local",
+            "sender": "@alice:test.local",
+            "origin_server_ts": 1,
+            "room_id": "!room:test.local",
+        });
+        if let Some(state_key) = state_key {
+            event["state_key"] = json!(state_key);
+        }
+        event
+    }
+
+    #[test]
+    fn test_parse_transaction_events() {
+        let parsed = TransactionEvent::parse(event(
+            "m.room.history_visibility",
+            Some(""),
+            json!({ "history_visibility": "world_readable" }),
+        ));
+        assert!(matches!(parsed.kind, AppserviceEvent::HistoryVisibility(_)));
+        assert!(parsed.is_state());
+
+        let parsed = TransactionEvent::parse(event(
+            "commune.public.room",
+            Some(""),
+            json!({ "public": true }),
+        ));
+        assert!(matches!(parsed.kind, AppserviceEvent::CommunePublicRoom(_)));
+
+        let parsed =
+            TransactionEvent::parse(event("m.room.topic", Some(""), json!({ "topic": "Hello" })));
+        assert!(matches!(parsed.kind, AppserviceEvent::State(_)));
+        assert_eq!(parsed.room_id(), Some("!room:test.local"));
+
+        let parsed = TransactionEvent::parse(event("m.reaction", None, json!({})));
+        assert!(matches!(parsed.kind, AppserviceEvent::Other));
+        assert!(!parsed.is_state());
+    }
+}
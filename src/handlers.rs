@@ -0,0 +1,681 @@
+use async_trait::async_trait;
+
+use ruma::events::room::history_visibility::HistoryVisibility;
+use ruma::events::room::member::MembershipState;
+use ruma::{OwnedRoomId, RoomId};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::AppState;
+use crate::admin::{JoinReason, record_join_reason};
+use crate::api::handle_recache;
+use crate::cache::{CacheError, CacheKey, StateCache, affected_caches};
+use crate::events::{AppserviceEvent, EventHandler, TransactionEvent};
+use crate::policy;
+use crate::rooms::{fetch_and_process_rooms, is_public_room_state};
+
+/// Joins rooms that become world readable, and child rooms added to spaces,
+/// when `auto_join` is enabled.
+pub struct AutoJoinHandler;
+
+#[async_trait]
+impl EventHandler for AutoJoinHandler {
+    fn name(&self) -> &'static str {
+        "auto_join"
+    }
+
+    fn handles(&self, state: &AppState, event: &TransactionEvent) -> bool {
+        if !state.config.appservice.rules.auto_join {
+            return false;
+        }
+
+        match &event.kind {
+            AppserviceEvent::HistoryVisibility(event) => {
+                event.history_visibility() == &HistoryVisibility::WorldReadable
+            }
+            AppserviceEvent::SpaceChild(_) => true,
+            _ => false,
+        }
+    }
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(room_id) = event.room_id()
+            && let Ok(room_id) = RoomId::parse(room_id)
+            && let Some(violation) = policy::access_violation(&state, &room_id)
+        {
+            tracing::info!("Not auto joining room {}: {:?}", room_id, violation);
+            return Ok(());
+        }
+
+        match &event.kind {
+            AppserviceEvent::HistoryVisibility(event) => {
+                tracing::info!("History Visibility: World Readable");
+
+                let room_id = event.room_id().to_owned();
+                tokio::spawn(async move {
+                    // delay for a moment to allow the event to be processed
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+
+                    tracing::info!("Joining room: {}", room_id);
+                    if let Err(e) = state.appservice.join_room(&room_id).await {
+                        tracing::warn!("Failed to join room: {}. Error: {}", room_id, e);
+                    } else {
+                        tracing::info!("Successfully joined room: {}", room_id);
+                        record_join_reason(&state, &room_id, JoinReason::WorldReadable).await;
+                    }
+                });
+            }
+            AppserviceEvent::SpaceChild(event) => {
+                tracing::info!("Auto joining space child room");
+
+                let room_id = event.room_id().to_owned();
+                tokio::spawn(async move {
+                    tracing::info!("Joining room: {}", room_id);
+                    if let Err(e) = state.appservice.join_room(&room_id).await {
+                        tracing::warn!("Failed to join room: {}. Error: {}", room_id, e);
+                    } else {
+                        tracing::info!("Successfully joined room: {}", room_id);
+                        record_join_reason(&state, &room_id, JoinReason::SpaceChild).await;
+                    }
+                });
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Joins or leaves rooms when their `commune.public.room` event is toggled.
+pub struct PublicRoomHandler;
+
+#[async_trait]
+impl EventHandler for PublicRoomHandler {
+    fn name(&self) -> &'static str {
+        "public_room"
+    }
+
+    fn handles(&self, _state: &AppState, event: &TransactionEvent) -> bool {
+        matches!(event.kind, AppserviceEvent::CommunePublicRoom(_))
+    }
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error> {
+        let AppserviceEvent::CommunePublicRoom(public_event) = &event.kind else {
+            return Ok(());
+        };
+
+        tracing::info!("Commune Public room event.");
+        let room_id = public_event.room_id().to_owned();
+        let cache_key = ("appservice:joined", room_id.as_str()).cache_key();
+
+        match event.raw["content"]["public"].as_bool() {
+            Some(true) if let Some(violation) = policy::access_violation(&state, &room_id) => {
+                tracing::info!("Not joining public room {}: {:?}", room_id, violation);
+            }
+            Some(true) => {
+                tracing::info!("Joining room: {}", room_id);
+                let joined = state
+                    .appservice
+                    .join_room(&room_id)
+                    .await
+                    .with_context(|| format!("Failed to join room: {room_id}"))?;
+                record_join_reason(&state, &room_id, JoinReason::PublicRoomEvent).await;
+
+                // cache the joined status
+                if (state.cache.cache_data(&cache_key, &joined, 300).await).is_ok() {
+                    tracing::info!("Cached joined status for room: {}", room_id);
+                } else {
+                    tracing::warn!("Failed to cache joined status for room: {}", room_id);
+                }
+            }
+            Some(false) => {
+                tracing::info!("Leaving room: {}", room_id);
+                let left = state.appservice.leave_room(&room_id).await;
+
+                if let Err(e) = state.cache.delete_cached_data(&cache_key).await {
+                    tracing::warn!(
+                        "Failed to delete room from cache: {}. Error: {}",
+                        room_id,
+                        e
+                    );
+                }
+
+                left.with_context(|| format!("Failed to leave room: {room_id}"))?;
+                tracing::info!("Successfully left room: {}", room_id);
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Drops the cached policy decision for a room when a state event that the
+/// policy depends on changes.
+pub struct PolicyHandler;
+
+#[async_trait]
+impl EventHandler for PolicyHandler {
+    fn name(&self) -> &'static str {
+        "policy"
+    }
+
+    fn handles(&self, _state: &AppState, event: &TransactionEvent) -> bool {
+        event.is_state() && policy::affects_policy(event.event_type())
+    }
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(room_id) = event.room_id() {
+            policy::invalidate(&state, room_id).await;
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps cached room directory, space and `/state` data in step with state
+/// events. Lists that are expensive to build are rebuilt in the background,
+/// the rest is deleted and refetched on the next request.
+pub struct CacheInvalidationHandler;
+
+#[async_trait]
+impl EventHandler for CacheInvalidationHandler {
+    fn name(&self) -> &'static str {
+        "cache_invalidation"
+    }
+
+    fn handles(&self, _state: &AppState, event: &TransactionEvent) -> bool {
+        event.is_state()
+    }
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error> {
+        for cache in affected_caches(event.event_type()) {
+            match cache {
+                StateCache::PublicRooms
+                    if state.config.cache.public_rooms.enabled && !state.index.is_ready() =>
+                {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let ttl = state.config.cache.public_rooms.ttl;
+                        let refreshed = state
+                            .cache
+                            .refresh_entry("public_rooms", || async {
+                                Ok((fetch_and_process_rooms(state.clone()).await, ttl))
+                            })
+                            .await;
+                        if let Err(e) = refreshed {
+                            tracing::warn!("Failed to rebuild public rooms: {}", e);
+                        }
+                    });
+                }
+                StateCache::PublicSpaces if state.config.spaces.cache => {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let ttl = state.config.spaces.ttl;
+                        let refreshed = state
+                            .cache
+                            .refresh_entry("public_spaces", || async {
+                                let spaces = state
+                                    .appservice
+                                    .get_public_spaces()
+                                    .await
+                                    .map_err(|e| CacheError::Fetch(e.to_string()))?
+                                    .unwrap_or_default();
+                                Ok((spaces, ttl))
+                            })
+                            .await;
+                        if let Err(e) = refreshed {
+                            tracing::warn!("Failed to rebuild public spaces: {}", e);
+                        }
+                    });
+                }
+                StateCache::SpaceSummaries => {
+                    state.cache.delete_by_prefix("space_summary").await?;
+                }
+                StateCache::SpaceRooms => {
+                    state.cache.delete_by_prefix("space_rooms").await?;
+                }
+                StateCache::RoomState => {
+                    if let Some(room_id) = event.room_id() {
+                        state
+                            .cache
+                            .delete_room_state(&state.config.matrix.homeserver, room_id)
+                            .await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps the public room index up to date with the appservice user's joins
+/// and leaves, and with state changes in indexed rooms.
+pub struct IndexHandler;
+
+#[async_trait]
+impl EventHandler for IndexHandler {
+    fn name(&self) -> &'static str {
+        "index"
+    }
+
+    fn handles(&self, state: &AppState, event: &TransactionEvent) -> bool {
+        if !state.index.is_ready() || !event.is_state() {
+            return false;
+        }
+
+        // other users' membership changes update the joined member count
+        matches!(event.kind, AppserviceEvent::Member(_)) || is_public_room_state(event.event_type())
+    }
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error> {
+        let Some(room_id) = event.room_id() else {
+            return Ok(());
+        };
+        let curated = state.config.public_rooms.curated;
+        let own_membership = event.state_key() == Some(state.appservice.user_id().as_str());
+
+        let changed = match &event.kind {
+            AppserviceEvent::Member(member_event) if own_membership => match member_event
+                .membership()
+            {
+                // curated directories only list the configured rooms and
+                // their children, which are indexed when they're added
+                MembershipState::Join if curated && !state.index.contains(room_id) => false,
+                MembershipState::Join => {
+                    state
+                        .index
+                        .add_room(&state, member_event.room_id().to_owned())
+                        .await?;
+                    true
+                }
+                MembershipState::Leave | MembershipState::Ban => state.index.remove_room(room_id),
+                _ => false,
+            },
+            AppserviceEvent::SpaceChild(child_event)
+                if curated && state.index.contains(room_id) =>
+            {
+                let changed = state.index.apply_state_event(room_id, &event.raw);
+
+                let has_via = event.raw["content"]["via"]
+                    .as_array()
+                    .is_some_and(|via| !via.is_empty());
+
+                let child_id = child_event.state_key();
+                if has_via && !state.index.contains(child_id.as_str()) {
+                    match state.index.add_room(&state, child_id.to_owned()).await {
+                        Ok(()) => state.index.persist_room(&state, child_id.as_str()).await,
+                        Err(e) => {
+                            tracing::warn!("Failed to index child room {}: {}", child_id, e);
+                        }
+                    }
+                }
+
+                changed
+            }
+            _ => state.index.apply_state_event(room_id, &event.raw),
+        };
+
+        match &event.kind {
+            // member counts change too often to write the room every time
+            AppserviceEvent::Member(_) if changed && !own_membership => {
+                state.index.mark_dirty(room_id);
+            }
+            _ if changed => state.index.persist_room(&state, room_id).await,
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Refreshes the cached `/messages` response when new messages or redactions
+/// arrive.
+pub struct RecacheHandler;
+
+#[async_trait]
+impl EventHandler for RecacheHandler {
+    fn name(&self) -> &'static str {
+        "recache"
+    }
+
+    fn handles(&self, _state: &AppState, event: &TransactionEvent) -> bool {
+        matches!(
+            event.kind,
+            AppserviceEvent::Message(_) | AppserviceEvent::Redaction(_)
+        )
+    }
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error> {
+        let (room_id, is_redaction) = match &event.kind {
+            AppserviceEvent::Message(event) => (event.room_id().to_string(), false),
+            AppserviceEvent::Redaction(event) => (event.room_id().to_string(), true),
+            _ => return Ok(()),
+        };
+
+        tokio::spawn(async move {
+            handle_recache(state, room_id, is_redaction).await;
+        });
+
+        Ok(())
+    }
+}
+
+/// Feeds new messages into the local search index, and drops redacted
+/// messages and rooms the appservice leaves.
+pub struct SearchIndexHandler;
+
+#[async_trait]
+impl EventHandler for SearchIndexHandler {
+    fn name(&self) -> &'static str {
+        "search_index"
+    }
+
+    fn handles(&self, state: &AppState, event: &TransactionEvent) -> bool {
+        if state.search.is_none() {
+            return false;
+        }
+
+        match event.kind {
+            AppserviceEvent::Message(_) | AppserviceEvent::Redaction(_) => true,
+            AppserviceEvent::Member(_) => {
+                event.state_key() == Some(state.appservice.user_id().as_str())
+            }
+            _ => false,
+        }
+    }
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error> {
+        let Some(search) = state.search.as_ref() else {
+            return Ok(());
+        };
+
+        match &event.kind {
+            AppserviceEvent::Message(_) => {
+                search.index_event(&event.raw);
+            }
+            AppserviceEvent::Redaction(_) => {
+                // `redacts` moved into the content in room version 11
+                let redacts = event.raw["redacts"]
+                    .as_str()
+                    .or_else(|| event.raw["content"]["redacts"].as_str());
+                if let Some(redacts) = redacts {
+                    search.remove_event(redacts);
+                }
+            }
+            AppserviceEvent::Member(member_event) => {
+                if matches!(
+                    member_event.membership(),
+                    MembershipState::Leave | MembershipState::Ban
+                ) && let Some(room_id) = event.room_id()
+                {
+                    search.remove_room(room_id);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps the moderation policy lists up to date from rule changes in the
+/// policy rooms, and loads a policy room's rules once it's joined.
+pub struct ModerationHandler;
+
+#[async_trait]
+impl EventHandler for ModerationHandler {
+    fn name(&self) -> &'static str {
+        "moderation"
+    }
+
+    fn handles(&self, state: &AppState, event: &TransactionEvent) -> bool {
+        if !event
+            .room_id()
+            .is_some_and(|room_id| state.moderation.is_policy_room(room_id))
+        {
+            return false;
+        }
+
+        match event.kind {
+            AppserviceEvent::Redaction(_) => true,
+            AppserviceEvent::Member(_) => {
+                event.state_key() == Some(state.appservice.user_id().as_str())
+            }
+            _ => event.is_state() && event.event_type().starts_with("m.policy.rule."),
+        }
+    }
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error> {
+        let Some(room_id) = event.room_id() else {
+            return Ok(());
+        };
+
+        match &event.kind {
+            AppserviceEvent::Redaction(_) => {
+                let redacts = event.raw["redacts"]
+                    .as_str()
+                    .or_else(|| event.raw["content"]["redacts"].as_str());
+                if let Some(redacts) = redacts
+                    && state.moderation.remove_event(room_id, redacts)
+                {
+                    tracing::info!("Removed redacted policy rule {} in {}", redacts, room_id);
+                }
+            }
+            AppserviceEvent::Member(member_event) => {
+                if member_event.membership() == &MembershipState::Join {
+                    let room_id = member_event.room_id().to_owned();
+                    state
+                        .moderation
+                        .load_room(&state, &room_id)
+                        .await
+                        .with_context(|| format!("Failed to load policy room: {room_id}"))?;
+                    tracing::info!("Loaded policy rules from {}", room_id);
+                }
+            }
+            _ => {
+                if state.moderation.apply_event(room_id, &event.raw) {
+                    tracing::info!(
+                        "Updated {} policy rule {:?} in {}",
+                        event.event_type(),
+                        event.state_key(),
+                        room_id
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Declines an invite, or leaves the room if it was already joined, with the
+/// rejection as the reason.
+async fn reject_invite(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+    rejection: policy::InviteRejection,
+) -> Result<(), anyhow::Error> {
+    tracing::info!("Rejecting invite to room {}: {}", room_id, rejection);
+    state
+        .appservice
+        .reject_invite(room_id, &rejection.to_string())
+        .await
+        .with_context(|| format!("Failed to reject invite: {room_id}"))
+}
+
+/// Handles invites, leaves and bans for the appservice user.
+pub struct MembershipHandler;
+
+#[async_trait]
+impl EventHandler for MembershipHandler {
+    fn name(&self) -> &'static str {
+        "membership"
+    }
+
+    fn handles(&self, state: &AppState, event: &TransactionEvent) -> bool {
+        // Ignore membership events for other users
+        matches!(event.kind, AppserviceEvent::Member(_))
+            && event.state_key() == Some(state.appservice.user_id().as_str())
+    }
+
+    async fn handle(
+        &self,
+        state: Arc<AppState>,
+        event: &TransactionEvent,
+    ) -> Result<(), anyhow::Error> {
+        let AppserviceEvent::Member(member_event) = &event.kind else {
+            return Ok(());
+        };
+
+        let room_id = member_event.room_id().to_owned();
+        let membership = member_event.membership().to_owned();
+
+        let Some(server_name) = member_event.room_id().server_name() else {
+            tracing::info!("Ignoring event for room with no server name");
+            return Ok(());
+        };
+
+        match membership {
+            MembershipState::Invite if state.moderation.is_policy_room(room_id.as_str()) => {
+                // configured policy rooms are trusted by the operator, and are
+                // often private or on other servers, so they skip the screening
+                tracing::info!("Joining policy room: {}", room_id);
+                state
+                    .appservice
+                    .join_room(&room_id)
+                    .await
+                    .with_context(|| format!("Failed to join policy room: {room_id}"))?;
+                tracing::info!("Successfully joined policy room: {}", room_id);
+
+                record_join_reason(&state, &room_id, JoinReason::Invite).await;
+
+                state.appservice.add_to_joined_rooms(room_id)?;
+            }
+            MembershipState::Invite
+                if !policy::server_allowed(&state.config, server_name.as_str()) =>
+            {
+                let rejection = policy::InviteRejection::DomainNotAllowed(server_name.to_string());
+                reject_invite(&state, &room_id, rejection).await?;
+            }
+            MembershipState::Invite if state.room_lists.is_blocked(room_id.as_str()) => {
+                reject_invite(&state, &room_id, policy::InviteRejection::Blocked).await?;
+            }
+            MembershipState::Invite if !state.room_lists.is_allowed(room_id.as_str()) => {
+                reject_invite(&state, &room_id, policy::InviteRejection::NotAllowlisted).await?;
+            }
+            MembershipState::Invite => {
+                // the invite state comes from the inviting server and isn't
+                // authenticated, so its power levels only allow an early reject
+                let screened = policy::screen_inviter(&state.config, &event.raw)
+                    .and_then(|()| policy::screen_invite(&event.raw))
+                    .and_then(|()| match policy::invite_power_levels(&event.raw) {
+                        Some(power_levels) => policy::check_inviter_power_level(
+                            &state.config,
+                            power_levels,
+                            member_event.sender().as_str(),
+                        ),
+                        None => Ok(()),
+                    });
+                if let Err(rejection) = screened {
+                    return reject_invite(&state, &room_id, rejection).await;
+                }
+
+                tracing::info!("Joining room: {}", room_id);
+                state
+                    .appservice
+                    .join_room(&room_id)
+                    .await
+                    .with_context(|| format!("Failed to join room: {room_id}"))?;
+                tracing::info!("Successfully joined room: {}", room_id);
+
+                // the room's own power levels are checked once joined, and the
+                // room is left if the inviter falls short
+                if state
+                    .config
+                    .appservice
+                    .rules
+                    .min_inviter_power_level
+                    .is_some()
+                {
+                    // unverifiable inviters are treated as unprivileged
+                    let power_levels = match state.appservice.get_power_levels(&room_id).await {
+                        Ok(power_levels) => power_levels,
+                        Err(e) => {
+                            tracing::warn!("Failed to get power levels for {}: {}", room_id, e);
+                            serde_json::Value::Null
+                        }
+                    };
+
+                    let checked = policy::check_inviter_power_level(
+                        &state.config,
+                        &power_levels,
+                        member_event.sender().as_str(),
+                    );
+                    if let Err(rejection) = checked {
+                        return reject_invite(&state, &room_id, rejection).await;
+                    }
+                }
+
+                record_join_reason(&state, &room_id, JoinReason::Invite).await;
+
+                state.appservice.add_to_joined_rooms(room_id)?;
+            }
+            // joins through any path, so the tracked rooms stay complete
+            MembershipState::Join => {
+                state.appservice.add_to_joined_rooms(room_id)?;
+            }
+            MembershipState::Leave => {
+                let left = state.appservice.leave_room(&room_id).await;
+                state.appservice.remove_from_joined_rooms(&room_id)?;
+
+                left.with_context(|| format!("Failed to leave room: {room_id}"))?;
+                tracing::info!("Successfully left room: {}", room_id);
+            }
+            MembershipState::Ban => {
+                tracing::info!("Banned from room: {}", room_id);
+                state.appservice.remove_from_joined_rooms(&room_id)?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+use ruma::OwnedRoomId;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::AppState;
+use crate::cache::{CacheError, CacheKey, unix_now};
+use crate::config::Config;
+use crate::rooms::{PublicRoom, is_public_room_state, sort_curated};
+
+/// Cache key marking when the index was last built. Rooms are persisted
+/// under it, one key per room, so restarts don't have to fetch the state of
+/// every joined room again.
+pub const INDEX_CACHE_KEY: &str = "public_room_index";
+
+/// How long until the index is rebuilt from scratch on startup.
+const INDEX_TTL: u64 = 60 * 60 * 24 * 7;
+
+// rooms outlive the marker, stale ones are removed when it's rebuilt
+const INDEX_ROOM_TTL: u64 = INDEX_TTL * 2;
+
+/// How often rooms whose member counts changed are written back.
+pub const INDEX_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+fn room_key(room_id: &str) -> String {
+    (INDEX_CACHE_KEY, room_id).cache_key()
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct IndexedRoom {
+    /// The state events the directory entry is built from, keyed by type and
+    /// state key.
+    state: BTreeMap<String, Value>,
+    /// Joined members, tracked on their own since only the count is listed.
+    #[serde(default)]
+    joined: BTreeSet<String>,
+    room: PublicRoom,
+}
+
+impl IndexedRoom {
+    fn new<'a>(room_id: &str, events: impl IntoIterator<Item = &'a Value>) -> Self {
+        let mut indexed = Self::default();
+        for event in events {
+            indexed.insert(event);
+        }
+        indexed.refresh(room_id);
+        indexed
+    }
+
+    fn refresh(&mut self, room_id: &str) {
+        self.room = PublicRoom::from_state(room_id, self.state.values());
+        self.room.num_joined_members = self.joined.len() as u64;
+    }
+
+    fn insert(&mut self, event: &Value) -> bool {
+        let (Some(event_type), Some(state_key)) =
+            (event["type"].as_str(), event["state_key"].as_str())
+        else {
+            return false;
+        };
+
+        if event_type == "m.room.member" {
+            return match event["content"]["membership"].as_str() {
+                Some("join") => self.joined.insert(state_key.to_string()),
+                _ => self.joined.remove(state_key),
+            };
+        }
+
+        if !is_public_room_state(event_type) {
+            return false;
+        }
+
+        self.state
+            .insert(format!("{event_type}\u{0}{state_key}"), event.clone());
+        true
+    }
+
+    fn apply(&mut self, room_id: &str, event: &Value) -> bool {
+        if !self.insert(event) {
+            return false;
+        }
+        self.refresh(room_id);
+        true
+    }
+}
+
+/// Directory entries for the rooms `/publicRooms` lists, built once and then
+/// kept up to date from transaction events instead of refetching the state of
+/// every room.
+#[derive(Clone, Default)]
+pub struct RoomIndex {
+    rooms: Arc<RwLock<HashMap<String, IndexedRoom>>>,
+    ready: Arc<AtomicBool>,
+    /// Rooms changed in memory only, waiting for the next flush.
+    dirty: Arc<Mutex<HashSet<String>>>,
+}
+
+impl RoomIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the index has been loaded and can serve `/publicRooms`.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    pub fn contains(&self, room_id: &str) -> bool {
+        self.read().contains_key(room_id)
+    }
+
+    pub fn room_ids(&self) -> Vec<OwnedRoomId> {
+        self.read()
+            .keys()
+            .filter_map(|room_id| OwnedRoomId::try_from(room_id.as_str()).ok())
+            .collect()
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<String, IndexedRoom>> {
+        self.rooms.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<String, IndexedRoom>> {
+        self.rooms.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Loads the persisted index, or builds it from the homeserver if there
+    /// isn't one or it's due to be rebuilt.
+    pub async fn load_or_build(&self, state: &AppState) {
+        match self.load(state).await {
+            Ok(Some(rooms)) => {
+                tracing::info!("Loaded public room index ({} rooms)", rooms.len());
+                *self.write() = rooms;
+                self.ready.store(true, Ordering::Release);
+            }
+            Ok(None) => {
+                self.rebuild(state).await;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to load public room index: {}", e);
+                self.rebuild(state).await;
+            }
+        }
+    }
+
+    async fn load(
+        &self,
+        state: &AppState,
+    ) -> Result<Option<HashMap<String, IndexedRoom>>, CacheError> {
+        if state
+            .cache
+            .get_cached_data::<u64>(INDEX_CACHE_KEY)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+
+        let mut rooms = HashMap::new();
+        for key in state.cache.keys_with_prefix(&room_key("")).await? {
+            if let Some(indexed) = state.cache.get_cached_data::<IndexedRoom>(&key).await? {
+                rooms.insert(indexed.room.room_id().to_string(), indexed);
+            }
+        }
+
+        Ok(Some(rooms))
+    }
+
+    /// Fetches the state of every joined (or curated) room and replaces the
+    /// index with it.
+    pub async fn rebuild(&self, state: &AppState) -> usize {
+        let rooms = match state.appservice.joined_rooms_state().await {
+            Ok(rooms) => rooms.unwrap_or_default(),
+            Err(e) => {
+                tracing::error!("Failed to build public room index: {}", e);
+                return self.len();
+            }
+        };
+
+        let rooms: HashMap<String, IndexedRoom> = rooms
+            .into_iter()
+            .map(|room| {
+                let events: Vec<Value> = room
+                    .state
+                    .iter()
+                    .flatten()
+                    .filter_map(|event| serde_json::from_str(event.json().get()).ok())
+                    .collect();
+
+                let room_id = room.room_id.to_string();
+                let indexed = IndexedRoom::new(&room_id, &events);
+                (room_id, indexed)
+            })
+            .collect();
+
+        let count = rooms.len();
+        let room_ids: Vec<String> = rooms.keys().cloned().collect();
+        *self.write() = rooms;
+        self.ready.store(true, Ordering::Release);
+
+        tracing::info!("Built public room index ({} rooms)", count);
+        if let Err(e) = self.persist_all(state, &room_ids).await {
+            tracing::warn!("Failed to persist public room index: {}", e);
+        }
+
+        count
+    }
+
+    /// Replaces the persisted index with the rooms in memory.
+    async fn persist_all(&self, state: &AppState, room_ids: &[String]) -> Result<(), CacheError> {
+        let stale: Vec<String> = state
+            .cache
+            .keys_with_prefix(&room_key(""))
+            .await?
+            .into_iter()
+            .filter(|key| !room_ids.iter().any(|room_id| *key == room_key(room_id)))
+            .collect();
+        state.cache.delete_keys(&stale).await?;
+
+        for room_id in room_ids {
+            self.persist_room(state, room_id).await;
+        }
+
+        state
+            .cache
+            .cache_data(INDEX_CACHE_KEY, &unix_now(), INDEX_TTL)
+            .await
+    }
+
+    /// Marks a room to be persisted on the next flush instead of right away.
+    pub fn mark_dirty(&self, room_id: &str) {
+        self.dirty
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(room_id.to_string());
+    }
+
+    /// Persists the rooms marked dirty since the last flush.
+    pub async fn flush(&self, state: &AppState) {
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap_or_else(|e| e.into_inner()));
+        for room_id in dirty {
+            self.persist_room(state, &room_id).await;
+        }
+    }
+
+    /// Writes a room's entry to the cache, or deletes it if the room is no
+    /// longer indexed.
+    pub async fn persist_room(&self, state: &AppState, room_id: &str) {
+        let indexed = self.read().get(room_id).cloned();
+
+        let persisted = match indexed {
+            Some(indexed) => {
+                state
+                    .cache
+                    .cache_data(&room_key(room_id), &indexed, INDEX_ROOM_TTL)
+                    .await
+            }
+            None => state.cache.delete_cached_data(&room_key(room_id)).await,
+        };
+
+        if let Err(e) = persisted {
+            tracing::warn!("Failed to persist indexed room {}: {}", room_id, e);
+        }
+    }
+
+    /// Applies a state event to an indexed room. Returns whether the index
+    /// changed.
+    pub fn apply_state_event(&self, room_id: &str, event: &Value) -> bool {
+        match self.write().get_mut(room_id) {
+            Some(indexed) => indexed.apply(room_id, event),
+            None => false,
+        }
+    }
+
+    /// Adds a room, or refreshes it if it's already indexed.
+    pub async fn add_room(&self, state: &AppState, room_id: OwnedRoomId) -> anyhow::Result<()> {
+        let room_state = state.appservice.get_room_state(room_id.clone()).await?;
+
+        let events: Vec<Value> = room_state
+            .iter()
+            .filter_map(|event| serde_json::from_str(event.json().get()).ok())
+            .collect();
+
+        let indexed = IndexedRoom::new(room_id.as_str(), &events);
+        self.write().insert(room_id.to_string(), indexed);
+
+        Ok(())
+    }
+
+    pub fn remove_room(&self, room_id: &str) -> bool {
+        self.write().remove(room_id).is_some()
+    }
+
+    /// The listed rooms, in curated order when configured.
+    pub fn public_rooms(&self, config: &Config) -> Vec<PublicRoom> {
+        let mut rooms: Vec<PublicRoom> = self
+            .read()
+            .values()
+            .map(|indexed| indexed.room.clone())
+            .filter(PublicRoom::is_listed)
+            .collect();
+
+        // stable output regardless of hash map order
+        rooms.sort_by(|a, b| a.room_id().cmp(b.room_id()));
+        sort_curated(config, &mut rooms);
+
+        rooms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::state_event;
+    use serde_json::json;
+
+    #[test]
+    fn test_indexed_room_applies_state_changes() {
+        let events = [
+            state_event("commune.room.name", "", json!({ "name": "Commune name" })),
+            state_event("m.room.name", "", json!({ "name": "Matrix name" })),
+            state_event(
+                "m.space.child",
+                "!child:test.local",
+                json!({ "via": ["test.local"] }),
+            ),
+        ];
+        let mut indexed = IndexedRoom::new("!room:test.local", &events);
+
+        let room = serde_json::to_value(&indexed.room).unwrap();
+        assert_eq!(room["name"], "Commune name");
+        assert_eq!(room["children"], json!(["!child:test.local"]));
+
+        // removing the child and changing the topic update the entry in place
+        assert!(indexed.apply(
+            "!room:test.local",
+            &state_event("m.space.child", "!child:test.local", json!({}))
+        ));
+        assert!(indexed.apply(
+            "!room:test.local",
+            &state_event("m.room.topic", "", json!({ "topic": "Hello" }))
+        ));
+        assert!(indexed.apply(
+            "!room:test.local",
+            &state_event(
+                "m.room.member",
+                "@bob:test.local",
+                json!({ "membership": "join" })
+            )
+        ));
+        assert!(!indexed.apply(
+            "!room:test.local",
+            &state_event(
+                "m.room.member",
+                "@carol:test.local",
+                json!({ "membership": "leave" })
+            )
+        ));
+        assert!(!indexed.apply(
+            "!room:test.local",
+            &state_event("m.room.power_levels", "", json!({}))
+        ));
+
+        let room = serde_json::to_value(&indexed.room).unwrap();
+        assert_eq!(room["topic"], "Hello");
+        assert_eq!(room["num_joined_members"], 1);
+        assert!(room.get("children").is_none());
+    }
+}
@@ -1,17 +1,30 @@
+pub mod admin;
 pub mod api;
 pub mod appservice;
+pub mod backend;
 pub mod cache;
 pub mod config;
 pub mod error;
+pub mod events;
+pub mod handlers;
+pub mod index;
 pub mod log;
+pub mod media;
 pub mod middleware;
+pub mod moderation;
 pub mod ping;
+pub mod policy;
 pub mod requests;
+pub mod roomlist;
 pub mod rooms;
+pub mod search;
 pub mod server;
 pub mod space;
 pub mod utils;
 
+#[cfg(test)]
+mod testing;
+
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -26,12 +39,18 @@ pub struct AppState {
     pub appservice: appservice::AppService,
     pub transaction_store: ping::TransactionStore,
     pub cache: cache::Cache,
+    pub media: Option<media::MediaCache>,
+    pub index: index::RoomIndex,
+    pub search: Option<search::SearchIndex>,
+    pub room_lists: roomlist::RoomLists,
+    pub moderation: moderation::PolicyLists,
+    pub dispatcher: events::EventDispatcher,
 }
 
 impl AppState {
     pub async fn new(config: config::Config) -> Result<Arc<Self>, anyhow::Error> {
         let client = Client::builder()
-            .timeout(Duration::from_secs(30))
+            .read_timeout(Duration::from_secs(30))
             .connect_timeout(Duration::from_secs(10))
             .pool_max_idle_per_host(10)
             .pool_idle_timeout(Duration::from_secs(90))
@@ -42,14 +61,48 @@ impl AppState {
 
         let cache = cache::Cache::new(&config).await?;
 
+        let room_lists = roomlist::RoomLists::new();
+        if let Err(e) = room_lists.load(&cache).await {
+            tracing::warn!("Failed to load room lists: {}", e);
+        }
+
+        let media = match config.cache.media.enabled {
+            true => Some(media::MediaCache::new(&config)?),
+            false => None,
+        };
+
+        let search = match config.search.backend {
+            config::SearchBackend::Local if !config.search.disabled => {
+                Some(search::SearchIndex::new(&config))
+            }
+            _ => None,
+        };
+
         let transaction_store = ping::TransactionStore::new();
 
+        let mut dispatcher = events::EventDispatcher::new();
+        dispatcher.register(handlers::AutoJoinHandler);
+        dispatcher.register(handlers::PublicRoomHandler);
+        dispatcher.register(handlers::PolicyHandler);
+        dispatcher.register(handlers::CacheInvalidationHandler);
+        dispatcher.register(handlers::IndexHandler);
+        dispatcher.register(handlers::RecacheHandler);
+        dispatcher.register(handlers::SearchIndexHandler);
+        dispatcher.register(handlers::MembershipHandler);
+        dispatcher.register(handlers::ModerationHandler);
+
         Ok(Arc::new(Self {
             config,
             proxy: client,
             appservice,
             transaction_store,
             cache,
+            media,
+            index: index::RoomIndex::new(),
+            search,
+            room_lists,
+            moderation: moderation::PolicyLists::new(),
+            dispatcher,
         }))
     }
 }
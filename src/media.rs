@@ -0,0 +1,362 @@
+use sha2::{Digest, Sha256};
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+use crate::requests::CachedResponse;
+
+const MEDIA_PREFIX: &str = "/_matrix/client/v1/media/";
+
+/// Identifies a piece of media as served by the homeserver: the mxc server
+/// and media id, plus the thumbnail parameters or download filename, since
+/// those change the response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaKey {
+    pub server_name: String,
+    pub media_id: String,
+    pub variant: String,
+}
+
+impl MediaKey {
+    /// Parses `/_matrix/client/v1/media/{download,thumbnail}/{server}/{id}`
+    /// requests. Other media endpoints such as `preview_url` aren't cached.
+    pub fn from_request(path: &str, query: Option<&str>) -> Option<Self> {
+        let mut segments = path.strip_prefix(MEDIA_PREFIX)?.split('/');
+
+        let kind = segments.next()?;
+        let server_name = segments.next().filter(|s| !s.is_empty())?.to_string();
+        let media_id = segments.next().filter(|s| !s.is_empty())?.to_string();
+
+        let variant = match kind {
+            "download" => match segments.next() {
+                Some(filename) => format!("download/{filename}"),
+                None => "download".to_string(),
+            },
+            "thumbnail" => {
+                // only the parameters that change the thumbnail, in a fixed
+                // order so equivalent requests share an entry
+                let params: BTreeMap<&str, &str> = query
+                    .unwrap_or_default()
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .filter(|(name, _)| matches!(*name, "width" | "height" | "method" | "animated"))
+                    .collect();
+
+                let params: Vec<String> = params
+                    .into_iter()
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect();
+
+                format!("thumbnail?{}", params.join("&"))
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            server_name,
+            media_id,
+            variant,
+        })
+    }
+
+    /// File name of the cached entry, the sha256 of the key.
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.server_name.as_bytes());
+        hasher.update(b"/");
+        hasher.update(self.media_id.as_bytes());
+        hasher.update(b"/");
+        hasher.update(self.variant.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+fn is_digest(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[derive(Debug)]
+struct MediaEntry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct MediaIndex {
+    entries: HashMap<String, MediaEntry>,
+    // last_used tick -> digest, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    total_size: u64,
+}
+
+impl MediaIndex {
+    fn insert(&mut self, digest: &str, size: u64) {
+        self.remove(digest);
+        self.total_size += size;
+        self.entries
+            .insert(digest.to_string(), MediaEntry { size, last_used: 0 });
+        self.touch(digest);
+    }
+
+    fn touch(&mut self, digest: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(entry) = self.entries.get_mut(digest) {
+            self.recency.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.recency.insert(tick, digest.to_string());
+        }
+    }
+
+    fn remove(&mut self, digest: &str) -> bool {
+        match self.entries.remove(digest) {
+            Some(entry) => {
+                self.recency.remove(&entry.last_used);
+                self.total_size -= entry.size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops least recently used entries until `total_size` fits in
+    /// `max_size`, returning the evicted digests.
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.total_size > max_size {
+            let Some((_, digest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&digest) {
+                self.total_size -= entry.size;
+            }
+            evicted.push(digest);
+        }
+        evicted
+    }
+}
+
+/// Content-addressed media cache on disk. Each entry is a body file named
+/// after its [`MediaKey::digest`] and a `.json` sidecar with the status and
+/// headers, written atomically through a temporary file.
+#[derive(Debug, Clone)]
+pub struct MediaCache {
+    directory: PathBuf,
+    max_size: u64,
+    max_file_size: u64,
+    ttl: Duration,
+    index: Arc<Mutex<MediaIndex>>,
+}
+
+impl MediaCache {
+    pub fn new(config: &Config) -> Result<Self, anyhow::Error> {
+        let directory = config.media.directory.clone();
+        std::fs::create_dir_all(&directory)?;
+
+        let cache = Self {
+            directory,
+            max_size: config.media.max_size_mb * 1024 * 1024,
+            max_file_size: config.media.max_file_size_mb * 1024 * 1024,
+            ttl: Duration::from_secs(config.cache.media.ttl),
+            index: Arc::new(Mutex::new(MediaIndex::default())),
+        };
+        cache.load_index()?;
+
+        Ok(cache)
+    }
+
+    /// Rebuilds the index from the files already on disk, oldest first so
+    /// they're the first to be evicted.
+    fn load_index(&self) -> Result<(), anyhow::Error> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let name = entry.file_name();
+
+            // skips sidecars and temporary files left by interrupted writes
+            let Some(digest) = name.to_str().filter(|name| is_digest(name)) else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, digest.to_string(), metadata.len()));
+        }
+        files.sort();
+
+        let mut index = self.lock_index();
+        for (_, digest, size) in &files {
+            index.insert(digest, *size);
+        }
+        let evicted = index.evict(self.max_size);
+        drop(index);
+
+        for digest in evicted {
+            self.remove_files(&digest);
+        }
+
+        tracing::info!("Loaded {} cached media files", files.len());
+        Ok(())
+    }
+
+    fn lock_index(&self) -> std::sync::MutexGuard<'_, MediaIndex> {
+        self.index.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn body_path(&self, digest: &str) -> PathBuf {
+        self.directory.join(digest)
+    }
+
+    fn meta_path(&self, digest: &str) -> PathBuf {
+        self.directory.join(format!("{digest}.json"))
+    }
+
+    fn remove_files(&self, digest: &str) {
+        let _ = std::fs::remove_file(self.body_path(digest));
+        let _ = std::fs::remove_file(self.meta_path(digest));
+    }
+
+    fn is_expired(&self, path: &Path) -> bool {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_none_or(|age| age > self.ttl)
+    }
+
+    /// Whether a file of this size may be cached.
+    pub fn accepts(&self, size: u64) -> bool {
+        size <= self.max_file_size
+    }
+
+    pub async fn get(&self, key: &MediaKey) -> Option<CachedResponse> {
+        let digest = key.digest();
+        if !self.lock_index().entries.contains_key(&digest) {
+            return None;
+        }
+
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || cache.read(&digest))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    fn read(&self, digest: &str) -> Option<CachedResponse> {
+        let body_path = self.body_path(digest);
+
+        if self.is_expired(&body_path) {
+            self.lock_index().remove(digest);
+            self.remove_files(digest);
+            return None;
+        }
+
+        let meta = std::fs::read(self.meta_path(digest)).ok()?;
+        let mut response: CachedResponse = serde_json::from_slice(&meta).ok()?;
+        response.body = std::fs::read(&body_path).ok()?;
+
+        self.lock_index().touch(digest);
+        Some(response)
+    }
+
+    /// Stores a successful response, unless it's larger than the per-file
+    /// limit, then evicts least recently used entries over the size cap.
+    pub async fn put(&self, key: &MediaKey, response: CachedResponse) {
+        if !response.is_success() || !self.accepts(response.body.len() as u64) {
+            return;
+        }
+
+        let digest = key.digest();
+        let cache = self.clone();
+        let written = tokio::task::spawn_blocking(move || cache.write(&digest, &response)).await;
+
+        match written {
+            Ok(Err(e)) => tracing::warn!("Failed to cache media {}: {}", key.media_id, e),
+            Err(e) => tracing::warn!("Failed to cache media {}: {}", key.media_id, e),
+            Ok(Ok(())) => {}
+        }
+    }
+
+    fn write(&self, digest: &str, response: &CachedResponse) -> Result<(), anyhow::Error> {
+        let meta = CachedResponse {
+            status: response.status,
+            headers: response.headers.clone(),
+            body: Vec::new(),
+        };
+
+        // sidecar first, a body without one is treated as a miss
+        self.write_atomic(&self.meta_path(digest), &serde_json::to_vec(&meta)?)?;
+        self.write_atomic(&self.body_path(digest), &response.body)?;
+
+        let mut index = self.lock_index();
+        index.insert(digest, response.body.len() as u64);
+        let evicted = index.evict(self.max_size);
+        drop(index);
+
+        for digest in evicted {
+            tracing::debug!("Evicting cached media {}", digest);
+            self.remove_files(&digest);
+        }
+
+        Ok(())
+    }
+
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> Result<(), anyhow::Error> {
+        let mut file = tempfile::NamedTempFile::new_in(&self.directory)?;
+        file.write_all(data)?;
+        file.persist(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_key_from_request() {
+        let key = MediaKey::from_request(
+            "/_matrix/client/v1/media/thumbnail/test.local/abc",
+            Some("width=64&height=64&method=crop&foo=bar"),
+        )
+        .unwrap();
+        assert_eq!(key.server_name, "test.local");
+        assert_eq!(key.media_id, "abc");
+        assert_eq!(key.variant, "thumbnail?height=64&method=crop&width=64");
+
+        let reordered = MediaKey::from_request(
+            "/_matrix/client/v1/media/thumbnail/test.local/abc",
+            Some("method=crop&height=64&width=64"),
+        )
+        .unwrap();
+        assert_eq!(key.digest(), reordered.digest());
+
+        let download =
+            MediaKey::from_request("/_matrix/client/v1/media/download/test.local/abc", None)
+                .unwrap();
+        assert_ne!(key.digest(), download.digest());
+
+        assert!(MediaKey::from_request("/_matrix/client/v1/media/preview_url", None).is_none());
+        assert!(
+            MediaKey::from_request("/_matrix/client/v1/media/download/test.local", None).is_none()
+        );
+    }
+
+    #[test]
+    fn test_media_index_evicts_least_recently_used() {
+        let mut index = MediaIndex::default();
+        index.insert("a", 40);
+        index.insert("b", 40);
+        index.touch("a");
+        index.insert("c", 40);
+
+        assert_eq!(index.evict(100), vec!["b".to_string()]);
+        assert_eq!(index.total_size, 80);
+        assert!(index.entries.contains_key("a"));
+    }
+}
@@ -7,18 +7,23 @@ use axum::{
     response::IntoResponse,
 };
 
-use ruma::{OwnedRoomId, RoomAliasId, RoomId};
+use http::Method;
+
+use ruma::{RoomAliasId, RoomId};
+
+use sha2::{Digest, Sha256};
 
 use serde_json::{Value, json};
 
 use std::sync::Arc;
 
 use crate::AppState;
-use crate::utils::{is_valid_room_id, room_alias_like};
+use crate::config::{AdminScope, AdminToken};
+use crate::utils::{constant_time_eq, is_valid_room_id, room_alias_like};
 
 use crate::error::AppserviceError;
 
-use crate::cache::CacheKey;
+use crate::policy;
 
 pub fn extract_token(header: &str) -> Option<&str> {
     header.strip_prefix("Bearer ").map(|token| token.trim())
@@ -54,8 +59,39 @@ pub async fn authenticate_homeserver(
     Ok(next.run(req).await)
 }
 
+pub fn required_admin_scope(method: &Method) -> AdminScope {
+    match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS => AdminScope::Read,
+        _ => AdminScope::Write,
+    }
+}
+
+/// Checks a bearer token against the configured admin token hashes. Every
+/// configured hash is compared so the time taken doesn't depend on which one
+/// matched.
+pub fn verify_admin_token(tokens: &[AdminToken], token: &str, required: AdminScope) -> bool {
+    let hash = format!("{:x}", Sha256::digest(token.as_bytes()));
+
+    let mut authorized = false;
+
+    for admin_token in tokens {
+        let matches = constant_time_eq(
+            hash.as_bytes(),
+            admin_token.hash.trim().to_lowercase().as_bytes(),
+        );
+
+        // write access implies read access
+        let scoped = admin_token.scopes.contains(&required)
+            || admin_token.scopes.contains(&AdminScope::Write);
+
+        authorized |= matches && scoped;
+    }
+
+    authorized
+}
+
 pub async fn is_admin(
-    //State(state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     req: Request<Body>,
     next: Next,
 ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
@@ -68,7 +104,14 @@ pub async fn is_admin(
 
     let token = extract_token(token).ok_or(unauthorized_error())?;
 
-    if token != "test" {
+    let required = required_admin_scope(req.method());
+
+    if !verify_admin_token(&state.config.admin.tokens, token, required) {
+        tracing::warn!(
+            "Rejected admin request: {} {}",
+            req.method(),
+            req.uri().path()
+        );
         return Err(unauthorized_error());
     }
 
@@ -214,9 +257,14 @@ pub async fn validate_public_room(
     let parsed_room_id = RoomId::parse(room_id)
         .map_err(|_| AppserviceError::AppserviceError("Invalid room ID".to_string()))?;
 
-    let is_joined = check_room_membership(&state, room_id, parsed_room_id).await?;
+    let decision = policy::check_room(&state, &parsed_room_id).await;
 
-    if !is_joined {
+    if !decision.is_public() {
+        tracing::info!(
+            "Room {} failed public policy: {:?}",
+            room_id,
+            decision.violations
+        );
         return Err(AppserviceError::AppserviceError(
             "Not a public room".to_string(),
         ));
@@ -225,52 +273,30 @@ pub async fn validate_public_room(
     Ok(next.run(req).await)
 }
 
-async fn check_room_membership(
-    state: &AppState,
-    room_id: &str,
-    parsed_room_id: OwnedRoomId,
-) -> Result<bool, AppserviceError> {
-    if !state.config.cache.joined_rooms.enabled {
-        return check_membership_direct(state, room_id, parsed_room_id).await;
-    }
-
-    let cache_key = ("appservice:joined", room_id).cache_key();
-
-    match state.cache.get_cached_data::<bool>(&cache_key).await {
-        Ok(Some(cached_result)) => {
-            tracing::info!("Using cached joined status for room: {}", room_id);
-            Ok(cached_result)
-        }
-        Ok(None) | Err(_) => {
-            let joined = check_membership_direct(state, room_id, parsed_room_id).await?;
-
-            if joined {
-                cache_membership_result(state, &cache_key, room_id).await;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            Ok(joined)
+    fn admin_token(token: &str, scopes: Vec<AdminScope>) -> AdminToken {
+        AdminToken {
+            name: None,
+            hash: format!("{:x}", Sha256::digest(token.as_bytes())),
+            scopes,
         }
     }
-}
-
-async fn check_membership_direct(
-    state: &AppState,
-    room_id: &str,
-    parsed_room_id: OwnedRoomId,
-) -> Result<bool, AppserviceError> {
-    state
-        .appservice
-        .has_joined_room(&parsed_room_id)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to check joined status for room {}: {}", room_id, e);
-            AppserviceError::AppserviceError("Failed to check joined status".to_string())
-        })
-}
 
-async fn cache_membership_result(state: &AppState, cache_key: &str, room_id: &str) {
-    match state.cache.cache_data(cache_key, &true, 300).await {
-        Ok(_) => tracing::info!("Cached joined status for room: {}", room_id),
-        Err(_) => tracing::warn!("Failed to cache joined status for room: {}", room_id),
+    #[test]
+    fn test_verify_admin_token() {
+        let tokens = vec![
+            admin_token("reader", vec![AdminScope::Read]),
+            admin_token("operator", vec![AdminScope::Write]),
+        ];
+
+        assert!(verify_admin_token(&tokens, "reader", AdminScope::Read));
+        assert!(!verify_admin_token(&tokens, "reader", AdminScope::Write));
+        assert!(verify_admin_token(&tokens, "operator", AdminScope::Read));
+        assert!(verify_admin_token(&tokens, "operator", AdminScope::Write));
+        assert!(!verify_admin_token(&tokens, "test", AdminScope::Read));
+        assert!(!verify_admin_token(&[], "reader", AdminScope::Read));
     }
 }
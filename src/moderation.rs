@@ -0,0 +1,482 @@
+use ruma::{OwnedRoomId, RoomAliasId, RoomId};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use crate::AppState;
+use crate::utils::glob_match;
+
+/// Recommendations that hide an entity, `m.ban` is the only one in the spec
+/// but older lists still use the mjolnir name.
+const BAN_RECOMMENDATIONS: [&str; 2] = ["m.ban", "org.matrix.mjolnir.ban"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    User,
+    Room,
+    Server,
+}
+
+impl RuleKind {
+    pub fn from_event_type(event_type: &str) -> Option<Self> {
+        match event_type {
+            "m.policy.rule.user" => Some(Self::User),
+            "m.policy.rule.room" => Some(Self::Room),
+            "m.policy.rule.server" => Some(Self::Server),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PolicyRule {
+    pub kind: RuleKind,
+    /// Glob matched against the user ID, room ID or server name.
+    pub entity: String,
+    pub recommendation: String,
+    pub reason: Option<String>,
+    pub event_id: Option<String>,
+}
+
+impl PolicyRule {
+    fn from_event(kind: RuleKind, event: &Value) -> Option<Self> {
+        let content = &event["content"];
+        Some(Self {
+            kind,
+            entity: content["entity"].as_str()?.to_string(),
+            recommendation: content["recommendation"].as_str()?.to_string(),
+            reason: content["reason"].as_str().map(str::to_string),
+            event_id: event["event_id"].as_str().map(str::to_string),
+        })
+    }
+
+    fn is_ban(&self) -> bool {
+        BAN_RECOMMENDATIONS.contains(&self.recommendation.as_str())
+    }
+
+    fn matches(&self, kind: RuleKind, value: &str) -> bool {
+        self.kind == kind
+            && self.is_ban()
+            && match kind {
+                RuleKind::Server => glob_match(
+                    &self.entity.to_ascii_lowercase(),
+                    &value.to_ascii_lowercase(),
+                    false,
+                ),
+                _ => glob_match(&self.entity, value, false),
+            }
+    }
+}
+
+/// Which proxied responses carry events that policy rules hide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeratedEndpoint {
+    Messages,
+    Context,
+    Event,
+    Threads,
+    Relations,
+    InitialSync,
+}
+
+impl ModeratedEndpoint {
+    /// Matches `/_matrix/client/{version}/rooms/{room_id}/{endpoint}` paths.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let path = path.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        match segments.as_slice() {
+            ["_matrix", "client", _, "rooms", _, "messages"] => Some(Self::Messages),
+            ["_matrix", "client", _, "rooms", _, "context", ..] => Some(Self::Context),
+            ["_matrix", "client", _, "rooms", _, "event", ..] => Some(Self::Event),
+            ["_matrix", "client", _, "rooms", _, "threads"] => Some(Self::Threads),
+            ["_matrix", "client", _, "rooms", _, "relations", ..] => Some(Self::Relations),
+            ["_matrix", "client", _, "rooms", _, "initialSync"] => Some(Self::InitialSync),
+            _ => None,
+        }
+    }
+}
+
+/// Ordered from least to most hidden, so outcomes combine with `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Moderated {
+    Unchanged,
+    /// Some events were dropped from the response.
+    Filtered,
+    /// The event the response is about is hidden, so it shouldn't be served.
+    Hidden,
+}
+
+type RuleKey = (String, String, String);
+
+/// Bans from `m.policy.rule.*` state in the configured policy list rooms.
+/// Banned users' events are dropped from proxied responses, and banned rooms
+/// and servers are treated as not public.
+#[derive(Debug, Clone, Default)]
+pub struct PolicyLists {
+    policy_rooms: Arc<RwLock<HashSet<String>>>,
+    // keyed by policy room, event type and state key
+    rules: Arc<RwLock<HashMap<RuleKey, PolicyRule>>>,
+}
+
+impl PolicyLists {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn rules(&self) -> std::sync::RwLockReadGuard<'_, HashMap<RuleKey, PolicyRule>> {
+        self.rules.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn rules_mut(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<RuleKey, PolicyRule>> {
+        self.rules.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules().is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules().len()
+    }
+
+    pub fn is_policy_room(&self, room_id: &str) -> bool {
+        self.policy_rooms
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(room_id)
+    }
+
+    /// Resolves the configured policy rooms and loads their rules. Rooms the
+    /// appservice hasn't joined yet are picked up once it joins them.
+    pub async fn load(&self, state: &AppState) {
+        for entry in &state.config.moderation.policy_rooms {
+            let room_id = match resolve_policy_room(state, entry).await {
+                Ok(room_id) => room_id,
+                Err(e) => {
+                    tracing::warn!("Failed to resolve policy room {}: {}", entry, e);
+                    continue;
+                }
+            };
+
+            self.policy_rooms
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(room_id.to_string());
+
+            if let Err(e) = self.load_room(state, &room_id).await {
+                tracing::warn!("Failed to load policy room {}: {}", room_id, e);
+            }
+        }
+
+        tracing::info!("Loaded {} moderation policy rules", self.len());
+    }
+
+    pub async fn load_room(
+        &self,
+        state: &AppState,
+        room_id: &OwnedRoomId,
+    ) -> Result<(), anyhow::Error> {
+        let room_state = state.appservice.get_room_state(room_id.clone()).await?;
+
+        for event in room_state {
+            if let Ok(event) = serde_json::from_str::<Value>(event.json().get()) {
+                self.apply_event(room_id.as_str(), &event);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies a policy rule state event from a policy room, an event without
+    /// an entity or recommendation removes the rule. Returns whether anything
+    /// changed.
+    pub fn apply_event(&self, room_id: &str, event: &Value) -> bool {
+        if !self.is_policy_room(room_id) {
+            return false;
+        }
+
+        let (Some(event_type), Some(state_key)) =
+            (event["type"].as_str(), event["state_key"].as_str())
+        else {
+            return false;
+        };
+        let Some(kind) = RuleKind::from_event_type(event_type) else {
+            return false;
+        };
+
+        let key = (
+            room_id.to_string(),
+            event_type.to_string(),
+            state_key.to_string(),
+        );
+
+        match PolicyRule::from_event(kind, event) {
+            Some(rule) => self.rules_mut().insert(key, rule.clone()) != Some(rule),
+            None => self.rules_mut().remove(&key).is_some(),
+        }
+    }
+
+    /// Removes the rule a redaction in a policy room stripped.
+    pub fn remove_event(&self, room_id: &str, event_id: &str) -> bool {
+        let mut rules = self.rules_mut();
+        let before = rules.len();
+        rules.retain(|(policy_room, _, _), rule| {
+            policy_room != room_id || rule.event_id.as_deref() != Some(event_id)
+        });
+        rules.len() != before
+    }
+
+    fn matches(&self, kind: RuleKind, value: &str) -> bool {
+        self.rules().values().any(|rule| rule.matches(kind, value))
+    }
+
+    pub fn is_user_banned(&self, user_id: &str) -> bool {
+        self.matches(RuleKind::User, user_id)
+            || user_id
+                .split_once(':')
+                .is_some_and(|(_, server)| self.matches(RuleKind::Server, server))
+    }
+
+    pub fn is_room_banned(&self, room_id: &RoomId) -> bool {
+        self.matches(RuleKind::Room, room_id.as_str())
+            || room_id
+                .server_name()
+                .is_some_and(|server| self.matches(RuleKind::Server, server.as_str()))
+    }
+
+    fn is_hidden(&self, event: &Value) -> bool {
+        event["sender"]
+            .as_str()
+            .is_some_and(|sender| self.is_user_banned(sender))
+    }
+
+    fn retain_events(&self, events: Option<&mut Value>) -> bool {
+        let Some(events) = events.and_then(Value::as_array_mut) else {
+            return false;
+        };
+        let before = events.len();
+        events.retain(|event| !self.is_hidden(event));
+        events.len() != before
+    }
+
+    /// Drops events by banned senders from a proxied response body.
+    pub fn moderate(&self, endpoint: ModeratedEndpoint, body: &mut Value) -> Moderated {
+        let filtered = match endpoint {
+            ModeratedEndpoint::Event => {
+                if self.is_hidden(body) {
+                    return Moderated::Hidden;
+                }
+                false
+            }
+            ModeratedEndpoint::Context => {
+                if self.is_hidden(&body["event"]) {
+                    return Moderated::Hidden;
+                }
+                let before = self.retain_events(body.get_mut("events_before"));
+                let after = self.retain_events(body.get_mut("events_after"));
+                before || after
+            }
+            ModeratedEndpoint::Messages | ModeratedEndpoint::Relations => {
+                self.retain_events(body.get_mut("chunk"))
+            }
+            ModeratedEndpoint::InitialSync => {
+                self.retain_events(body.pointer_mut("/messages/chunk"))
+            }
+            ModeratedEndpoint::Threads => {
+                let mut filtered = self.retain_events(body.get_mut("chunk"));
+                // thread roots can be fine while the latest reply isn't
+                for root in body
+                    .get_mut("chunk")
+                    .and_then(Value::as_array_mut)
+                    .into_iter()
+                    .flatten()
+                {
+                    if let Some(thread) = root
+                        .pointer_mut("/unsigned/m.relations/m.thread")
+                        .and_then(Value::as_object_mut)
+                        && thread
+                            .get("latest_event")
+                            .is_some_and(|event| self.is_hidden(event))
+                    {
+                        thread.remove("latest_event");
+                        filtered = true;
+                    }
+                }
+                filtered
+            }
+        };
+
+        match filtered {
+            true => Moderated::Filtered,
+            false => Moderated::Unchanged,
+        }
+    }
+}
+
+async fn resolve_policy_room(state: &AppState, entry: &str) -> Result<OwnedRoomId, anyhow::Error> {
+    if entry.starts_with('#') {
+        let alias = RoomAliasId::parse(entry)?;
+        return state.appservice.room_id_from_alias(alias).await;
+    }
+
+    Ok(RoomId::parse(entry)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy_lists() -> PolicyLists {
+        let lists = PolicyLists::new();
+        lists
+            .policy_rooms
+            .write()
+            .unwrap()
+            .insert("!policy:test.local".to_string());
+        lists
+    }
+
+    fn rule(event_type: &str, state_key: &str, entity: &str) -> Value {
+        json!({
+            "type": event_type,
+            "state_key": state_key,
+            "event_id": format!("${state_key}"),
+            "content": { "entity": entity, "recommendation": "m.ban", "reason": "spam" },
+        })
+    }
+
+    #[test]
+    fn test_policy_rules() {
+        let lists = policy_lists();
+
+        // rules from other rooms are ignored
+        assert!(!lists.apply_event(
+            "!other:test.local",
+            &rule("m.policy.rule.user", "u1", "@spam:test.local")
+        ));
+
+        assert!(lists.apply_event(
+            "!policy:test.local",
+            &rule("m.policy.rule.user", "u1", "@spam*:test.local")
+        ));
+        assert!(lists.apply_event(
+            "!policy:test.local",
+            &rule("m.policy.rule.server", "s1", "*.evil.test")
+        ));
+        assert!(lists.apply_event(
+            "!policy:test.local",
+            &rule("m.policy.rule.room", "r1", "!bad:test.local")
+        ));
+
+        assert!(lists.is_user_banned("@spammer:test.local"));
+        assert!(lists.is_user_banned("@anyone:a.EVIL.test"));
+        assert!(!lists.is_user_banned("@alice:test.local"));
+        assert!(lists.is_room_banned(&RoomId::parse("!bad:test.local").unwrap()));
+        assert!(lists.is_room_banned(&RoomId::parse("!room:a.evil.test").unwrap()));
+        assert!(!lists.is_room_banned(&RoomId::parse("!good:test.local").unwrap()));
+
+        // an emptied rule is removed, and so is a redacted one
+        assert!(lists.apply_event(
+            "!policy:test.local",
+            &json!({ "type": "m.policy.rule.room", "state_key": "r1", "content": {} })
+        ));
+        assert!(!lists.is_room_banned(&RoomId::parse("!bad:test.local").unwrap()));
+        assert!(lists.remove_event("!policy:test.local", "$s1"));
+        assert!(!lists.is_user_banned("@anyone:a.evil.test"));
+    }
+
+    #[test]
+    fn test_moderate() {
+        let lists = policy_lists();
+        lists.apply_event(
+            "!policy:test.local",
+            &rule("m.policy.rule.user", "u1", "@spam:test.local"),
+        );
+
+        let mut messages = json!({
+            "chunk": [
+                { "sender": "@alice:test.local" },
+                { "sender": "@spam:test.local" },
+            ]
+        });
+        assert_eq!(
+            lists.moderate(ModeratedEndpoint::Messages, &mut messages),
+            Moderated::Filtered
+        );
+        assert_eq!(messages["chunk"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            lists.moderate(ModeratedEndpoint::Messages, &mut messages),
+            Moderated::Unchanged
+        );
+
+        let mut initial_sync = json!({
+            "messages": { "chunk": [{ "sender": "@spam:test.local" }] },
+        });
+        assert_eq!(
+            lists.moderate(ModeratedEndpoint::InitialSync, &mut initial_sync),
+            Moderated::Filtered
+        );
+        assert!(
+            initial_sync["messages"]["chunk"]
+                .as_array()
+                .unwrap()
+                .is_empty()
+        );
+
+        let mut context = json!({ "event": { "sender": "@spam:test.local" } });
+        assert_eq!(
+            lists.moderate(ModeratedEndpoint::Context, &mut context),
+            Moderated::Hidden
+        );
+
+        let mut threads = json!({
+            "chunk": [{
+                "sender": "@alice:test.local",
+                "unsigned": { "m.relations": { "m.thread": {
+                    "count": 2,
+                    "latest_event": { "sender": "@spam:test.local" },
+                } } },
+            }]
+        });
+        assert_eq!(
+            lists.moderate(ModeratedEndpoint::Threads, &mut threads),
+            Moderated::Filtered
+        );
+        assert!(
+            threads["chunk"][0]["unsigned"]["m.relations"]["m.thread"]
+                .get("latest_event")
+                .is_none()
+        );
+
+        assert_eq!(
+            ModeratedEndpoint::from_path(
+                "/_matrix/client/v3/rooms/!a:test.local/context/$e?limit=5"
+            ),
+            Some(ModeratedEndpoint::Context)
+        );
+        assert_eq!(
+            ModeratedEndpoint::from_path("/_matrix/client/v1/rooms/!a:test.local/threads"),
+            Some(ModeratedEndpoint::Threads)
+        );
+        assert_eq!(
+            ModeratedEndpoint::from_path(
+                "/_matrix/client/v1/rooms/!a:test.local/relations/$e/m.thread"
+            ),
+            Some(ModeratedEndpoint::Relations)
+        );
+        assert_eq!(
+            ModeratedEndpoint::from_path("/_matrix/client/v3/rooms/!a:test.local/initialSync"),
+            Some(ModeratedEndpoint::InitialSync)
+        );
+        assert_eq!(
+            ModeratedEndpoint::from_path("/_matrix/client/v3/rooms/!a:test.local/state"),
+            None
+        );
+    }
+}
@@ -37,11 +37,11 @@ impl TransactionStore {
 
     pub async fn verify_and_remove_transaction(&self, transaction_id: &str) -> bool {
         let mut store = self.current_id.write().await;
-        if let Some(stored_id) = store.as_ref() {
-            if stored_id == transaction_id {
-                *store = None;
-                return true;
-            }
+        if let Some(stored_id) = store.as_ref()
+            && stored_id == transaction_id
+        {
+            *store = None;
+            return true;
         }
         false
     }
@@ -0,0 +1,662 @@
+use futures::StreamExt;
+
+use ruma::{
+    OwnedRoomId, RoomId,
+    events::room::history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::AppState;
+use crate::api::CommunePublicRoomEventContent;
+use crate::appservice::RoomState;
+use crate::config::Config;
+
+use crate::cache::CacheKey;
+
+/// Rooms checked at the same time by [`filter_public`].
+const POLICY_CONCURRENCY: usize = 10;
+
+/// State event types whose changes can flip a room's public status.
+pub const POLICY_STATE_TYPES: [&str; 4] = [
+    "m.room.history_visibility",
+    "m.room.encryption",
+    "m.room.member",
+    "commune.public.room",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyViolation {
+    NotJoined,
+    NotWorldReadable,
+    NotMarkedPublic,
+    Encrypted,
+    DirectMessage,
+    DomainNotAllowed,
+    Blocked,
+    NotAllowlisted,
+    PolicyRule,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PolicyDecision {
+    pub violations: Vec<PolicyViolation>,
+}
+
+impl PolicyDecision {
+    pub fn is_public(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+pub fn affects_policy(event_type: &str) -> bool {
+    POLICY_STATE_TYPES.contains(&event_type)
+}
+
+/// Evaluates the public room policy described in `docs/security.md` against
+/// the full state of a room.
+pub fn evaluate_room_state(
+    room_state: &RoomState,
+    user_id: &str,
+    require_public_event: bool,
+) -> PolicyDecision {
+    let mut joined = false;
+    let mut world_readable = false;
+    let mut marked_public = false;
+    let mut encrypted = false;
+    let mut creator = None;
+    // (sender, state_key) of member events that set `is_direct`
+    let mut direct_members = Vec::new();
+
+    for state_event in room_state {
+        let event_type = match state_event.get_field::<String>("type") {
+            Ok(Some(t)) => t,
+            _ => continue,
+        };
+
+        match event_type.as_str() {
+            "m.room.history_visibility" => {
+                if let Ok(Some(content)) =
+                    state_event.get_field::<RoomHistoryVisibilityEventContent>("content")
+                {
+                    world_readable = content.history_visibility == HistoryVisibility::WorldReadable;
+                }
+            }
+            // encryption can't be turned off once enabled, so any event counts
+            "m.room.encryption" => encrypted = true,
+            "m.room.create" => {
+                creator = state_event.get_field::<String>("sender").ok().flatten();
+            }
+            "commune.public.room" => {
+                if let Ok(Some(content)) =
+                    state_event.get_field::<CommunePublicRoomEventContent>("content")
+                {
+                    marked_public = content.public;
+                }
+            }
+            "m.room.member" => {
+                let Ok(Some(content)) = state_event.get_field::<Value>("content") else {
+                    continue;
+                };
+
+                let state_key = state_event.get_field::<String>("state_key").ok().flatten();
+
+                if content["is_direct"].as_bool() == Some(true)
+                    && let Ok(Some(sender)) = state_event.get_field::<String>("sender")
+                {
+                    direct_members.push((sender, state_key.clone()));
+                }
+
+                if state_key.as_deref() == Some(user_id) {
+                    joined = content["membership"].as_str() == Some("join");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // anyone can set `is_direct` on their own membership, so it only counts
+    // on the creator's membership and on the invites the creator sent
+    let direct = creator.is_some_and(|creator| {
+        direct_members.iter().any(|(sender, state_key)| {
+            *sender == creator || state_key.as_deref() == Some(creator.as_str())
+        })
+    });
+
+    let mut violations = Vec::new();
+
+    if !joined {
+        violations.push(PolicyViolation::NotJoined);
+    }
+    if !world_readable {
+        violations.push(PolicyViolation::NotWorldReadable);
+    }
+    if require_public_event && !marked_public {
+        violations.push(PolicyViolation::NotMarkedPublic);
+    }
+    if encrypted {
+        violations.push(PolicyViolation::Encrypted);
+    }
+    if direct {
+        violations.push(PolicyViolation::DirectMessage);
+    }
+
+    PolicyDecision { violations }
+}
+
+/// The host part of a server name, without the port.
+fn server_host(server_name: &str) -> &str {
+    match server_name.strip_prefix('[') {
+        // IPv6 literal
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => server_name.split(':').next().unwrap_or(server_name),
+    }
+}
+
+/// Matches a server name against `example.org`, or `*.example.org` for its
+/// subdomains. Case is ignored, and so is the port unless the pattern has one.
+pub fn domain_matches(pattern: &str, server_name: &str) -> bool {
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return false;
+    }
+
+    let host = server_host(server_name);
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            host.len() > suffix.len() + 1 && {
+                let (subdomain, domain) = host.split_at(host.len() - suffix.len());
+                subdomain.ends_with('.') && domain.eq_ignore_ascii_case(suffix)
+            }
+        }
+        None => pattern.eq_ignore_ascii_case(server_name) || pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Whether rooms on a server may be joined and served. The blocklist wins
+/// over the local server and the whitelist.
+pub fn server_allowed(config: &Config, server_name: &str) -> bool {
+    let rules = &config.appservice.rules;
+
+    if rules
+        .federation_domain_blocklist
+        .iter()
+        .any(|pattern| domain_matches(pattern, server_name))
+    {
+        return false;
+    }
+
+    server_name.eq_ignore_ascii_case(&config.matrix.server_name)
+        || rules
+            .federation_domain_whitelist
+            .iter()
+            .any(|pattern| domain_matches(pattern, server_name))
+}
+
+/// [`server_allowed`] for the server in a room ID. Room IDs without one are
+/// left to the rest of the policy.
+pub fn room_allowed(config: &Config, room_id: &RoomId) -> bool {
+    room_id
+        .server_name()
+        .is_none_or(|server_name| server_allowed(config, server_name.as_str()))
+}
+
+/// Checks a room against the domain rules, the runtime room lists and the
+/// moderation policy lists, which don't need its state.
+pub fn access_violation(state: &AppState, room_id: &RoomId) -> Option<PolicyViolation> {
+    if !room_allowed(&state.config, room_id) {
+        return Some(PolicyViolation::DomainNotAllowed);
+    }
+    if state.room_lists.is_blocked(room_id.as_str()) {
+        return Some(PolicyViolation::Blocked);
+    }
+    if !state.room_lists.is_allowed(room_id.as_str()) {
+        return Some(PolicyViolation::NotAllowlisted);
+    }
+    if state.moderation.is_room_banned(room_id) {
+        return Some(PolicyViolation::PolicyRule);
+    }
+    None
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum InviteRejection {
+    #[error("Direct message rooms cannot be made public")]
+    DirectMessage,
+    #[error("Encrypted rooms cannot be made public")]
+    Encrypted,
+    #[error("Rooms with history visibility '{0}' cannot be made public")]
+    HistoryVisibility(String),
+    #[error("Rooms on {0} cannot be made public")]
+    DomainNotAllowed(String),
+    #[error("This room is blocked")]
+    Blocked,
+    #[error("This room is not on the allowlist")]
+    NotAllowlisted,
+    #[error("Invites from {0} are not accepted")]
+    InviterNotAllowed(String),
+    #[error("Only local users can make rooms public")]
+    InviterNotLocal,
+    #[error("Inviter has power level {level}, {required} is required")]
+    InviterPowerLevel { level: i64, required: i64 },
+}
+
+/// Screens an invite for the appservice user, using `is_direct` on the member
+/// event and the stripped state the homeserver sends along in
+/// `unsigned.invite_room_state`. State that isn't included is not held against
+/// the room, it gets checked again by the policy evaluator once joined. Join
+/// rules aren't checked, world readable rooms are public whoever can join.
+pub fn screen_invite(member_event: &Value) -> Result<(), InviteRejection> {
+    if member_event["content"]["is_direct"].as_bool() == Some(true) {
+        return Err(InviteRejection::DirectMessage);
+    }
+
+    let Some(invite_state) = member_event["unsigned"]["invite_room_state"].as_array() else {
+        return Ok(());
+    };
+
+    for stripped_event in invite_state {
+        let content = &stripped_event["content"];
+
+        match stripped_event["type"].as_str() {
+            Some("m.room.encryption") => return Err(InviteRejection::Encrypted),
+            Some("m.room.history_visibility") => {
+                if let Some(visibility) = content["history_visibility"].as_str()
+                    && visibility != HistoryVisibility::WorldReadable.as_str()
+                {
+                    return Err(InviteRejection::HistoryVisibility(visibility.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Screens the sender of an invite for the appservice user: their server
+/// must pass the domain rules, and be the local one with `invite_by_local_user`.
+pub fn screen_inviter(config: &Config, member_event: &Value) -> Result<(), InviteRejection> {
+    let sender = member_event["sender"].as_str().unwrap_or_default();
+    let Some((_, server_name)) = sender.split_once(':') else {
+        return Err(InviteRejection::InviterNotAllowed(sender.to_string()));
+    };
+
+    if !server_allowed(config, server_name) {
+        return Err(InviteRejection::InviterNotAllowed(server_name.to_string()));
+    }
+
+    if config.appservice.rules.invite_by_local_user
+        && !server_name.eq_ignore_ascii_case(&config.matrix.server_name)
+    {
+        return Err(InviteRejection::InviterNotLocal);
+    }
+
+    Ok(())
+}
+
+fn power_level(value: &Value) -> Option<i64> {
+    // older room versions allow levels as strings
+    value
+        .as_i64()
+        .or_else(|| value.as_str().and_then(|level| level.trim().parse().ok()))
+}
+
+/// A user's power level from `m.room.power_levels` content.
+pub fn user_power_level(power_levels: &Value, user_id: &str) -> i64 {
+    power_levels["users"]
+        .get(user_id)
+        .and_then(power_level)
+        .or_else(|| power_level(&power_levels["users_default"]))
+        .unwrap_or(0)
+}
+
+/// The `m.room.power_levels` content from an invite's stripped state, if the
+/// homeserver included it.
+pub fn invite_power_levels(member_event: &Value) -> Option<&Value> {
+    member_event["unsigned"]["invite_room_state"]
+        .as_array()?
+        .iter()
+        .find(|event| event["type"] == "m.room.power_levels")
+        .map(|event| &event["content"])
+}
+
+/// Checks the inviter's power level against `min_inviter_power_level`.
+pub fn check_inviter_power_level(
+    config: &Config,
+    power_levels: &Value,
+    sender: &str,
+) -> Result<(), InviteRejection> {
+    let Some(required) = config.appservice.rules.min_inviter_power_level else {
+        return Ok(());
+    };
+
+    let level = user_power_level(power_levels, sender);
+    if level < required {
+        return Err(InviteRejection::InviterPowerLevel { level, required });
+    }
+
+    Ok(())
+}
+
+async fn evaluate_room(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+) -> Result<PolicyDecision, anyhow::Error> {
+    let room_state = state.appservice.get_room_state(room_id.clone()).await?;
+
+    Ok(evaluate_room_state(
+        &room_state,
+        &state.appservice.user_id(),
+        state.config.public_rooms.require_public_event,
+    ))
+}
+
+/// Returns the policy decision for a room, using the cached result when
+/// policy caching is enabled.
+pub async fn check_room(state: &AppState, room_id: &OwnedRoomId) -> PolicyDecision {
+    // cheap and changed at runtime, so checked before the cache
+    if let Some(violation) = access_violation(state, room_id) {
+        return PolicyDecision {
+            violations: vec![violation],
+        };
+    }
+
+    let cache_key = ("public_policy", room_id.as_str()).cache_key();
+
+    if state.config.cache.policy.enabled
+        && let Ok(Some(decision)) = state
+            .cache
+            .get_cached_data::<PolicyDecision>(&cache_key)
+            .await
+    {
+        return decision;
+    }
+
+    let decision = match evaluate_room(state, room_id).await {
+        Ok(decision) => decision,
+        Err(e) => {
+            // Don't cache this, the homeserver may just be unavailable
+            tracing::info!("Failed to fetch state for room {}: {}", room_id, e);
+            return PolicyDecision {
+                violations: vec![PolicyViolation::NotJoined],
+            };
+        }
+    };
+
+    if state.config.cache.policy.enabled
+        && let Err(e) = state
+            .cache
+            .cache_data(&cache_key, &decision, state.config.cache.policy.ttl)
+            .await
+    {
+        tracing::warn!("Failed to cache policy for room {}: {}", room_id, e);
+    }
+
+    decision
+}
+
+/// The rooms searched when a search doesn't name any. The room index follows
+/// the appservice's own joins and leaves, the joined rooms are only a
+/// fallback until it's ready.
+pub fn search_candidates(state: &AppState) -> Vec<OwnedRoomId> {
+    match state.index.is_ready() {
+        true => state.index.room_ids(),
+        false => state.appservice.joined_room_ids(),
+    }
+}
+
+/// Keeps the rooms that currently pass the public room policy.
+pub async fn filter_public(state: &AppState, room_ids: Vec<OwnedRoomId>) -> Vec<OwnedRoomId> {
+    futures::stream::iter(room_ids)
+        .map(|room_id| async move {
+            check_room(state, &room_id)
+                .await
+                .is_public()
+                .then_some(room_id)
+        })
+        .buffered(POLICY_CONCURRENCY)
+        .filter_map(futures::future::ready)
+        .collect()
+        .await
+}
+
+pub async fn invalidate(state: &AppState, room_id: &str) {
+    let cache_key = ("public_policy", room_id).cache_key();
+    if let Err(e) = state.cache.delete_cached_data(&cache_key).await {
+        tracing::warn!("Failed to invalidate policy for room {}: {}", room_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config_with_rules, state_event};
+    use serde_json::json;
+
+    fn room_state(events: Vec<Value>) -> RoomState {
+        events
+            .into_iter()
+            .map(|event| serde_json::from_value(event).expect("Should parse state event"))
+            .collect()
+    }
+
+    fn public_room() -> Vec<Value> {
+        vec![
+            state_event(
+                "m.room.history_visibility",
+                "",
+                json!({ "history_visibility": "world_readable" }),
+            ),
+            state_event("commune.public.room", "", json!({ "public": true })),
+            state_event(
+                "m.room.member",
+                "@public:test.local",
+                json!({ "membership": "join" }),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_domain_matches() {
+        assert!(domain_matches("matrix.org", "matrix.org"));
+        assert!(domain_matches("matrix.org", "Matrix.org:8448"));
+        assert!(!domain_matches("matrix.org", "evilmatrix.org"));
+        assert!(!domain_matches("matrix.org", "matrix.org.evil.com"));
+        assert!(!domain_matches("matrix.org:8448", "matrix.org"));
+
+        assert!(domain_matches("*.example.org", "chat.example.org"));
+        assert!(domain_matches("*.example.org", "a.b.example.org:443"));
+        assert!(!domain_matches("*.example.org", "example.org"));
+        assert!(!domain_matches("*.example.org", "badexample.org"));
+
+        assert!(domain_matches("::1", "[::1]:8448"));
+        assert!(!domain_matches("", "matrix.org"));
+    }
+
+    #[test]
+    fn test_server_allowed() {
+        let mut config = config_with_rules(
+            r#"
+            federation_domain_whitelist = ["*.example.org", "matrix.org"]
+            federation_domain_blocklist = ["bad.example.org"]
+        "#,
+        );
+
+        assert!(server_allowed(&config, "test.local"));
+        assert!(server_allowed(&config, "matrix.org"));
+        assert!(server_allowed(&config, "chat.example.org"));
+        assert!(!server_allowed(&config, "bad.example.org"));
+        assert!(!server_allowed(&config, "evilmatrix.org"));
+
+        config
+            .appservice
+            .rules
+            .federation_domain_blocklist
+            .push("test.local".to_string());
+        assert!(!server_allowed(&config, "test.local"));
+    }
+
+    #[test]
+    fn test_screen_inviter() {
+        let mut config = config_with_rules(
+            r#"
+            invite_by_local_user = true
+            federation_domain_whitelist = ["matrix.org"]
+            min_inviter_power_level = 50
+        "#,
+        );
+
+        let invite = |sender: &str| json!({ "sender": sender, "room_id": "!room:matrix.org" });
+
+        assert_eq!(
+            screen_inviter(&config, &invite("@alice:test.local")),
+            Ok(())
+        );
+        assert_eq!(
+            screen_inviter(&config, &invite("@bob:matrix.org")),
+            Err(InviteRejection::InviterNotLocal)
+        );
+        assert_eq!(
+            screen_inviter(&config, &invite("@eve:evil.org")),
+            Err(InviteRejection::InviterNotAllowed("evil.org".to_string()))
+        );
+
+        config.appservice.rules.invite_by_local_user = false;
+        assert_eq!(screen_inviter(&config, &invite("@bob:matrix.org")), Ok(()));
+
+        let power_levels = json!({
+            "users": { "@alice:test.local": 100, "@bob:matrix.org": "50" },
+            "users_default": 10,
+        });
+        assert_eq!(user_power_level(&power_levels, "@bob:matrix.org"), 50);
+        assert_eq!(user_power_level(&power_levels, "@carol:test.local"), 10);
+        assert_eq!(
+            check_inviter_power_level(&config, &power_levels, "@bob:matrix.org"),
+            Ok(())
+        );
+        assert_eq!(
+            check_inviter_power_level(&config, &power_levels, "@carol:test.local"),
+            Err(InviteRejection::InviterPowerLevel {
+                level: 10,
+                required: 50
+            })
+        );
+    }
+
+    #[test]
+    fn test_public_room_passes() {
+        let decision = evaluate_room_state(&room_state(public_room()), "@public:test.local", true);
+        assert!(decision.is_public());
+    }
+
+    #[test]
+    fn test_encrypted_direct_room_fails() {
+        let mut events = public_room();
+        events.push(state_event(
+            "m.room.encryption",
+            "",
+            json!({ "algorithm": "m.megolm.v1.aes-sha2" }),
+        ));
+        events.push(state_event("m.room.create", "", json!({})));
+        events.push(state_event(
+            "m.room.member",
+            "@bob:test.local",
+            json!({ "membership": "invite", "is_direct": true }),
+        ));
+
+        let decision = evaluate_room_state(&room_state(events), "@public:test.local", true);
+        assert_eq!(
+            decision.violations,
+            vec![PolicyViolation::Encrypted, PolicyViolation::DirectMessage]
+        );
+    }
+
+    #[test]
+    fn test_is_direct_from_other_members_ignored() {
+        let mut events = public_room();
+        events.push(state_event("m.room.create", "", json!({})));
+
+        let mut join = state_event(
+            "m.room.member",
+            "@mallory:test.local",
+            json!({ "membership": "join", "is_direct": true }),
+        );
+        join["sender"] = json!("@mallory:test.local");
+        events.push(join);
+
+        let decision = evaluate_room_state(&room_state(events), "@public:test.local", true);
+        assert!(decision.is_public());
+    }
+
+    #[test]
+    fn test_left_room_without_public_event() {
+        let events = vec![
+            state_event(
+                "m.room.history_visibility",
+                "",
+                json!({ "history_visibility": "shared" }),
+            ),
+            state_event(
+                "m.room.member",
+                "@public:test.local",
+                json!({ "membership": "leave" }),
+            ),
+        ];
+
+        let decision = evaluate_room_state(&room_state(events.clone()), "@public:test.local", true);
+        assert_eq!(
+            decision.violations,
+            vec![
+                PolicyViolation::NotJoined,
+                PolicyViolation::NotWorldReadable,
+                PolicyViolation::NotMarkedPublic,
+            ]
+        );
+
+        let decision = evaluate_room_state(&room_state(events), "@public:test.local", false);
+        assert!(
+            !decision
+                .violations
+                .contains(&PolicyViolation::NotMarkedPublic)
+        );
+    }
+
+    fn invite(is_direct: bool, invite_room_state: Vec<Value>) -> Value {
+        json!({
+            "type": "m.room.member",
+            "state_key": "@public:test.local",
+            "content": { "membership": "invite", "is_direct": is_direct },
+            "unsigned": { "invite_room_state": invite_room_state },
+        })
+    }
+
+    #[test]
+    fn test_screen_invite() {
+        let join_rule =
+            |rule: &str| json!({ "type": "m.room.join_rules", "content": { "join_rule": rule } });
+
+        assert_eq!(
+            screen_invite(&invite(false, vec![join_rule("public")])),
+            Ok(())
+        );
+        assert_eq!(
+            screen_invite(&invite(true, vec![])),
+            Err(InviteRejection::DirectMessage)
+        );
+        assert_eq!(
+            screen_invite(&invite(false, vec![join_rule("invite")])),
+            Ok(())
+        );
+        assert_eq!(
+            screen_invite(&invite(
+                false,
+                vec![json!({ "type": "m.room.encryption", "content": {} })]
+            )),
+            Err(InviteRejection::Encrypted)
+        );
+    }
+}
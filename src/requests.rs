@@ -1,20 +1,36 @@
 use axum::{
     Extension,
-    body::Body,
+    body::{Body, Bytes},
     extract::{OriginalUri, State},
-    http::{HeaderMap, Request, Response, StatusCode},
+    http::{
+        HeaderMap, Method, Request, Response, StatusCode,
+        header::{CONTENT_TYPE, RANGE},
+    },
 };
 
+use futures::StreamExt;
+
+use ruma::OwnedRoomId;
+
+use std::collections::HashSet;
 use std::time::Duration;
 
 use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
 use sha2::{Digest, Sha256};
 
 use crate::AppState;
 use crate::middleware::{Data, ProxyRequestType};
 
-use crate::cache::CacheKey;
+use crate::cache::{CacheEntry, CacheError, CacheKey};
+use crate::config::StateEvents;
+use crate::media::{MediaCache, MediaKey};
+use crate::moderation::{Moderated, ModeratedEndpoint};
+use crate::policy;
+use crate::utils::percent_decode;
 
 pub async fn matrix_proxy(
     Extension(data): Extension<Data>,
@@ -34,11 +50,25 @@ pub async fn matrix_proxy(
 
     let mut target_url = format!("{}{}", state.config.matrix.homeserver, path);
 
-    if data.modified_path.is_none() {
-        if let Some(query) = req.uri().query() {
-            target_url.push('?');
-            target_url.push_str(query);
-        }
+    if data.modified_path.is_none()
+        && let Some(query) = req.uri().query()
+    {
+        target_url.push('?');
+        target_url.push_str(query);
+    }
+
+    let filter = ResponseFilter::new(&state, path);
+
+    // partial content is streamed straight through, never cached
+    let is_range_request = headers.contains_key(RANGE);
+
+    if let ProxyRequestType::Media = data.proxy_request_type
+        && method == Method::GET
+        && !is_range_request
+        && let Some(media) = state.media.clone()
+        && let Some(key) = MediaKey::from_request(path, req.uri().query())
+    {
+        return proxy_media(state, media, key, headers, target_url).await;
     }
 
     let cache_key = ("proxy_request", target_url.as_str()).cache_key();
@@ -51,106 +81,374 @@ pub async fn matrix_proxy(
     };
 
     // skip if cache disabled by config for request type
-    if !state.config.cache.requests.enabled || skip_cache {
-        return proxy_request_no_cache(state, method, headers, target_url, req).await;
+    if !state.config.cache.requests.enabled || skip_cache || is_range_request {
+        return proxy_request_no_cache(state, method, headers, target_url, req, filter).await;
     }
 
-    if let Ok(Some(cached_response)) = state.cache.get_cached_data::<Vec<u8>>(&cache_key).await {
-        tracing::info!(
-            "Returning cached proxy response for {} ({} bytes)",
-            target_url,
-            cached_response.len()
-        );
+    let options = match data.proxy_request_type {
+        ProxyRequestType::RoomState => &state.config.cache.room_state,
+        ProxyRequestType::Messages => &state.config.cache.messages,
+        ProxyRequestType::Media | ProxyRequestType::Other => &state.config.cache.requests,
+    };
+    let (cache_ttl, stale_ttl) = (options.ttl, options.stale_ttl);
 
-        return Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .body(axum::body::Body::from(cached_response))
-            .map_err(|e| {
-                tracing::error!("Failed to build cached response: {}", e);
-                StatusCode::INTERNAL_SERVER_ERROR
-            });
-    }
+    let body_bytes = read_request_body(&state, req, &target_url).await?;
 
-    let cache_ttl = match data.proxy_request_type {
-        ProxyRequestType::RoomState => state.config.cache.room_state.ttl,
-        ProxyRequestType::Messages => state.config.cache.messages.ttl,
-        ProxyRequestType::Media => 0,
-        ProxyRequestType::Other => state.config.cache.requests.ttl,
+    let upstream = ProxyUpstream {
+        state: state.clone(),
+        method,
+        headers,
+        target_url,
+        body: body_bytes,
     };
 
+    let negative_ttl = state.config.cache.negative_ttl;
+    // successful responses are kept for the stale window past their TTL,
+    // error responses only for the negative TTL
+    let entry_ttls = move |response: &CachedResponse| {
+        let ttl = response.cache_ttl(cache_ttl, negative_ttl);
+        match response.is_success() {
+            true => (ttl, ttl + stale_ttl),
+            false => (ttl, ttl),
+        }
+    };
+
+    let cached = state
+        .cache
+        .get_cached_data::<CacheEntry<CachedResponse>>(&cache_key)
+        .await
+        .ok()
+        .flatten();
+
+    if let Some(entry) = cached {
+        if entry.is_fresh() {
+            tracing::info!(
+                "Returning cached proxy response for {} ({} bytes)",
+                upstream.target_url,
+                entry.data.body.len()
+            );
+            return filter.apply(&state, entry.data).into_response(false);
+        }
+
+        // serve the stale copy right away and refresh it in the background,
+        // if the homeserver is failing the stale copy keeps being served
+        // until it expires
+        tracing::info!("Returning stale proxy response for {}", upstream.target_url);
+        tokio::spawn(async move {
+            let cache = upstream.state.cache.clone();
+            let refreshed = cache
+                .refresh_entry(&cache_key, || async {
+                    let response = upstream.fetch().await?;
+                    // error responses don't replace the stale copy
+                    if !response.is_success() {
+                        return Err(CacheError::Fetch(format!(
+                            "homeserver returned {}",
+                            response.status
+                        )));
+                    }
+                    let (fresh_ttl, ttl) = entry_ttls(&response);
+                    Ok((CacheEntry::new(response, fresh_ttl), ttl))
+                })
+                .await;
+            if let Err(e) = refreshed {
+                tracing::warn!("Failed to refresh stale entry {}: {}", cache_key, e);
+            }
+        });
+        return filter.apply(&state, entry.data).into_response(true);
+    }
+
     // cache missed
-    let response_data = state
+    let target_url = upstream.target_url.clone();
+    let entry = state
         .cache
-        .cache_or_fetch(&cache_key, cache_ttl, || async {
+        .cache_or_fetch_with_ttl(&cache_key, || async {
             tracing::info!("Cache miss for proxy request: {}", target_url);
+            let response = upstream.fetch().await?;
+            let (fresh_ttl, ttl) = entry_ttls(&response);
+            Ok((CacheEntry::new(response, fresh_ttl), ttl))
+        })
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to get proxy response for {}: {}", target_url, e);
+            StatusCode::BAD_GATEWAY
+        })?;
 
-            let body_bytes = match axum::body::to_bytes(req.into_body(), usize::MAX).await {
-                Ok(bytes) => bytes,
-                Err(_) => {
-                    return Err(redis::RedisError::from((
-                        redis::ErrorKind::IoError,
-                        "Failed to read request body",
-                    )));
-                }
-            };
+    filter.apply(&state, entry.data).into_response(false)
+}
+
+async fn proxy_media(
+    state: Arc<AppState>,
+    media: MediaCache,
+    key: MediaKey,
+    headers: HeaderMap,
+    target_url: String,
+) -> Result<Response<Body>, StatusCode> {
+    if let Some(cached) = media.get(&key).await {
+        tracing::info!(
+            "Returning cached media {}/{} ({} bytes)",
+            key.server_name,
+            key.media_id,
+            cached.body.len()
+        );
+        return cached.into_response(false);
+    }
 
-            let mut request_builder = state
-                .proxy
-                .request(method.clone(), &target_url)
-                .timeout(Duration::from_secs(25))
-                .bearer_auth(&state.config.appservice.access_token);
+    let upstream = ProxyUpstream {
+        state,
+        method: Method::GET,
+        headers,
+        target_url,
+        body: Bytes::new(),
+    };
 
-            let mut filtered_headers = HeaderMap::new();
-            for (name, value) in headers.iter() {
-                if !is_hop_by_hop_header(name.as_str()) && name != "authorization" {
-                    filtered_headers.insert(name, value.clone());
-                }
-            }
+    let response = upstream.send(None).await.map_err(|e| {
+        tracing::error!("Failed to get media for {}: {}", upstream.target_url, e);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    // files too large for the cache, or of unknown size, are streamed
+    if !response
+        .content_length()
+        .is_some_and(|length| media.accepts(length))
+    {
+        return upstream.stream(response);
+    }
+
+    let response = upstream.read(response).await.map_err(|e| {
+        tracing::error!("Failed to get media for {}: {}", upstream.target_url, e);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    media.put(&key, response.clone()).await;
+
+    response.into_response(false)
+}
+
+/// Response headers kept when a proxied response is cached.
+const CACHED_HEADERS: [&str; 6] = [
+    "content-type",
+    "content-disposition",
+    "content-language",
+    "cache-control",
+    "etag",
+    "last-modified",
+];
+
+/// A proxied homeserver response as stored in the cache, so hits replay the
+/// original status and headers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl CachedResponse {
+    pub fn new(status: StatusCode, headers: &HeaderMap, body: Vec<u8>) -> Self {
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| CACHED_HEADERS.contains(&name.as_str()))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+
+        Self {
+            status: status.as_u16(),
+            headers,
+            body,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// How long the response may be cached: `ttl` for successful responses,
+    /// `negative_ttl` for client errors, and `0` (not at all) for rate limits
+    /// and server errors.
+    pub fn cache_ttl(&self, ttl: u64, negative_ttl: u64) -> u64 {
+        match StatusCode::from_u16(self.status) {
+            Ok(StatusCode::TOO_MANY_REQUESTS) => 0,
+            Ok(status) if status.is_success() || status.is_redirection() => ttl,
+            Ok(status) if status.is_client_error() => negative_ttl,
+            _ => 0,
+        }
+    }
 
-            request_builder = request_builder.headers(filtered_headers);
+    pub fn into_response(self, stale: bool) -> Result<Response<Body>, StatusCode> {
+        let mut response = Response::builder().status(self.status);
 
-            if !body_bytes.is_empty() {
-                request_builder = request_builder.body(body_bytes);
+        for (name, value) in &self.headers {
+            response = response.header(name, value);
+        }
+
+        if stale {
+            response = response.header("X-Cache-Status", "stale");
+        }
+
+        response
+            .body(axum::body::Body::from(self.body))
+            .map_err(|e| {
+                tracing::error!("Failed to build response: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+    }
+}
+
+/// Limit for proxied requests whose responses are read in full.
+const PROXY_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Everything needed to replay a proxied request against the homeserver,
+/// owned so it can be moved into a background refresh.
+struct ProxyUpstream {
+    state: Arc<AppState>,
+    method: Method,
+    headers: HeaderMap,
+    target_url: String,
+    body: Bytes,
+}
+
+impl ProxyUpstream {
+    /// Sends the request, bounded by `timeout` as a whole when it's given.
+    /// Responses that may be streamed are only bounded by the client's
+    /// connect and read timeouts, so long downloads aren't cut off.
+    async fn send(&self, timeout: Option<Duration>) -> Result<reqwest::Response, CacheError> {
+        let mut request_builder = self
+            .state
+            .proxy
+            .request(self.method.clone(), &self.target_url)
+            .bearer_auth(&self.state.config.appservice.access_token);
+
+        if let Some(timeout) = timeout {
+            request_builder = request_builder.timeout(timeout);
+        }
+
+        let mut filtered_headers = HeaderMap::new();
+        for (name, value) in self.headers.iter() {
+            if !is_hop_by_hop_header(name.as_str()) && name != "authorization" {
+                filtered_headers.insert(name, value.clone());
             }
+        }
 
-            let response = request_builder.send().await.map_err(|e| {
-                tracing::error!("Proxy request failed for {}: {}", target_url, e);
-                redis::RedisError::from((redis::ErrorKind::IoError, "Proxy request failed"))
-            })?;
-
-            let body = response.bytes().await.map_err(|e| {
-                tracing::error!(
-                    "Failed to read proxy response body for {}: {}",
-                    target_url,
-                    e
-                );
-                redis::RedisError::from((redis::ErrorKind::IoError, "Failed to read response body"))
-            })?;
-
-            let response_vec = body.to_vec();
-            tracing::info!(
-                "Fetched and cached proxy response for {} ({} bytes)",
-                target_url,
-                response_vec.len()
-            );
+        request_builder = request_builder.headers(filtered_headers);
+
+        if !self.body.is_empty() {
+            request_builder = request_builder.body(self.body.clone());
+        }
 
-            Ok(response_vec)
+        request_builder.send().await.map_err(|e| {
+            tracing::error!("Proxy request failed for {}: {}", self.target_url, e);
+            CacheError::Fetch("Proxy request failed".to_string())
         })
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to get proxy response for {}: {}", target_url, e);
-            StatusCode::BAD_GATEWAY
+    }
+
+    async fn fetch(&self) -> Result<CachedResponse, CacheError> {
+        let response = self.send(Some(PROXY_TIMEOUT)).await?;
+        self.read(response).await
+    }
+
+    /// Buffers the response, up to the configured maximum response size.
+    async fn read(&self, response: reqwest::Response) -> Result<CachedResponse, CacheError> {
+        let status = response.status();
+        let headers = response.headers().clone();
+        let limit = self.state.config.server.max_response_body();
+
+        let body = read_limited(response, limit).await.map_err(|e| {
+            tracing::error!(
+                "Failed to read proxy response body for {}: {}",
+                self.target_url,
+                e
+            );
+            CacheError::Fetch("Failed to read response body".to_string())
         })?;
 
-    Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "application/json")
-        .body(axum::body::Body::from(response_data))
-        .map_err(|e| {
+        tracing::info!(
+            "Fetched proxy response for {} ({}, {} bytes)",
+            self.target_url,
+            status,
+            body.len()
+        );
+
+        Ok(CachedResponse::new(status, &headers, body))
+    }
+
+    /// Passes the response through as it arrives, with its status and
+    /// headers, so large files aren't held in memory and ranges are kept.
+    fn stream(&self, response: reqwest::Response) -> Result<Response<Body>, StatusCode> {
+        let limit = self.state.config.server.max_response_body();
+
+        if response
+            .content_length()
+            .is_some_and(|length| length > limit)
+        {
+            tracing::warn!(
+                "Proxy response for {} exceeds the maximum size of {} bytes",
+                self.target_url,
+                limit
+            );
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+
+        let mut axum_response = Response::builder().status(response.status());
+
+        for (name, value) in response.headers().iter() {
+            if !is_hop_by_hop_header(name.as_str()) {
+                axum_response = axum_response.header(name, value);
+            }
+        }
+
+        // responses without a content length are cut off at the limit
+        let mut received: u64 = 0;
+        let stream = response.bytes_stream().map(move |chunk| {
+            let chunk = chunk.map_err(std::io::Error::other)?;
+            received += chunk.len() as u64;
+            if received > limit {
+                return Err(std::io::Error::other(
+                    "Response body exceeds the configured maximum",
+                ));
+            }
+            Ok(chunk)
+        });
+
+        axum_response.body(Body::from_stream(stream)).map_err(|e| {
             tracing::error!("Failed to build response: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })
+    }
+}
+
+async fn read_limited(mut response: reqwest::Response, limit: u64) -> Result<Vec<u8>, String> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit)
+    {
+        return Err(format!("Response body exceeds {limit} bytes"));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        if (body.len() + chunk.len()) as u64 > limit {
+            return Err(format!("Response body exceeds {limit} bytes"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// Reads the request body, rejecting bodies over the configured maximum.
+async fn read_request_body(
+    state: &AppState,
+    req: Request<Body>,
+    target_url: &str,
+) -> Result<Bytes, StatusCode> {
+    let limit = state.config.server.max_request_body();
+
+    axum::body::to_bytes(req.into_body(), limit)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to read request body for {}: {}", target_url, e);
+            StatusCode::PAYLOAD_TOO_LARGE
+        })
 }
 
 async fn proxy_request_no_cache(
@@ -159,59 +457,163 @@ async fn proxy_request_no_cache(
     headers: HeaderMap,
     target_url: String,
     req: Request<Body>,
+    filter: ResponseFilter,
 ) -> Result<Response<Body>, StatusCode> {
-    let body_bytes = match axum::body::to_bytes(req.into_body(), usize::MAX).await {
-        Ok(bytes) => bytes,
-        Err(_) => {
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+    let body = read_request_body(&state, req, &target_url).await?;
+
+    let upstream = ProxyUpstream {
+        state,
+        method,
+        headers,
+        target_url,
+        body,
     };
 
-    let mut request_builder = state
-        .proxy
-        .request(method, &target_url)
-        .timeout(Duration::from_secs(25))
-        .bearer_auth(&state.config.appservice.access_token);
+    let response = upstream
+        .send(None)
+        .await
+        .map_err(|_| StatusCode::BAD_GATEWAY)?;
 
-    let mut filtered_headers = HeaderMap::new();
-    for (name, value) in headers.iter() {
-        if !is_hop_by_hop_header(name.as_str()) && name != "authorization" {
-            filtered_headers.insert(name, value.clone());
+    // responses that may need filtering have to be read in full
+    if !filter.is_empty() {
+        let response = upstream
+            .read(response)
+            .await
+            .map_err(|_| StatusCode::BAD_GATEWAY)?;
+        return filter.apply(&upstream.state, response).into_response(false);
+    }
+
+    upstream.stream(response)
+}
+
+/// Proxied routes that serve room state, filtered by the configured state
+/// event types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum StateEndpoint {
+    State,
+    /// A single state event, by its decoded type.
+    StateEvent(Option<String>),
+    InitialSync,
+    Context,
+}
+
+impl StateEndpoint {
+    fn from_path(path: &str) -> Option<Self> {
+        let path = path.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        match segments.as_slice() {
+            ["_matrix", "client", _, "rooms", _, "state"] => Some(Self::State),
+            ["_matrix", "client", _, "rooms", _, "state", event_type, ..] => {
+                // decoded so an escaped type can't get past the lists
+                Some(Self::StateEvent(percent_decode(event_type)))
+            }
+            ["_matrix", "client", _, "rooms", _, "initialSync"] => Some(Self::InitialSync),
+            ["_matrix", "client", _, "rooms", _, "context", ..] => Some(Self::Context),
+            _ => None,
         }
     }
+}
 
-    request_builder = request_builder.headers(filtered_headers);
+/// What has to be hidden from a proxied response before it's served.
+/// Responses are cached as the homeserver sent them and filtered when
+/// they're served, so rule changes apply to cached copies too.
+#[derive(Debug, Clone, Default)]
+struct ResponseFilter {
+    moderated: Option<ModeratedEndpoint>,
+    state: Option<StateEndpoint>,
+}
 
-    if !body_bytes.is_empty() {
-        request_builder = request_builder.body(body_bytes);
+impl ResponseFilter {
+    fn new(state: &AppState, path: &str) -> Self {
+        Self {
+            moderated: ModeratedEndpoint::from_path(path).filter(|_| !state.moderation.is_empty()),
+            state: StateEndpoint::from_path(path)
+                .filter(|_| state.config.state_events.is_enabled()),
+        }
     }
 
-    let response = request_builder
-        .send()
-        .await
-        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+    fn is_empty(&self) -> bool {
+        self.moderated.is_none() && self.state.is_none()
+    }
 
-    let status = response.status();
-    let response_headers = response.headers().clone();
-    let body = response
-        .bytes()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    /// Hides events by senders banned in the moderation policy lists, and
+    /// state events of types that aren't served. A response about a single
+    /// hidden event becomes a `404`, as if it didn't exist.
+    fn apply(&self, state: &AppState, mut response: CachedResponse) -> CachedResponse {
+        if self.is_empty() || !response.is_success() {
+            return response;
+        }
+
+        if let Some(StateEndpoint::StateEvent(event_type)) = &self.state
+            && !event_type
+                .as_deref()
+                .is_some_and(|event_type| state.config.state_events.allows(event_type))
+        {
+            return not_found_response();
+        }
+
+        let Ok(mut body) = serde_json::from_slice::<Value>(&response.body) else {
+            return response;
+        };
+
+        let mut outcome = self
+            .moderated
+            .map(|endpoint| state.moderation.moderate(endpoint, &mut body))
+            .unwrap_or(Moderated::Unchanged);
 
-    let mut axum_response = Response::builder().status(status);
+        if let Some(endpoint) = &self.state {
+            let state_events = &state.config.state_events;
+            let filtered = match endpoint {
+                StateEndpoint::State => retain_state(state_events, Some(&mut body)),
+                StateEndpoint::InitialSync | StateEndpoint::Context => {
+                    retain_state(state_events, body.get_mut("state"))
+                }
+                StateEndpoint::StateEvent(_) => false,
+            };
+            if filtered {
+                outcome = outcome.max(Moderated::Filtered);
+            }
+        }
 
-    for (name, value) in response_headers.iter() {
-        if !is_hop_by_hop_header(name.as_str()) {
-            axum_response = axum_response.header(name, value);
+        match outcome {
+            Moderated::Unchanged => response,
+            Moderated::Filtered => {
+                if let Ok(filtered) = serde_json::to_vec(&body) {
+                    response.body = filtered;
+                    // the validator no longer matches the body
+                    response.headers.retain(|(name, _)| name != "etag");
+                }
+                response
+            }
+            Moderated::Hidden => not_found_response(),
         }
     }
+}
 
-    axum_response
-        .body(axum::body::Body::from(body))
-        .map_err(|e| {
-            tracing::error!("Failed to build response: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })
+/// Drops state events of types that aren't served, returning whether any
+/// were dropped.
+fn retain_state(state_events: &StateEvents, events: Option<&mut Value>) -> bool {
+    let Some(events) = events.and_then(Value::as_array_mut) else {
+        return false;
+    };
+    let before = events.len();
+    events.retain(|event| {
+        event["type"]
+            .as_str()
+            .is_some_and(|event_type| state_events.allows(event_type))
+    });
+    events.len() != before
+}
+
+fn not_found_response() -> CachedResponse {
+    CachedResponse {
+        status: StatusCode::NOT_FOUND.as_u16(),
+        headers: vec![(CONTENT_TYPE.to_string(), "application/json".to_string())],
+        body: json!({ "errcode": "M_NOT_FOUND", "error": "Event not found" })
+            .to_string()
+            .into_bytes(),
+    }
 }
 
 pub async fn matrix_proxy_search(
@@ -232,20 +634,33 @@ pub async fn matrix_proxy_search(
 
     let mut target_url = format!("{}{}", state.config.matrix.homeserver, path);
 
-    if data.modified_path.is_none() {
-        if let Some(query) = req.uri().query() {
-            target_url.push('?');
-            target_url.push_str(query);
-        }
+    if data.modified_path.is_none()
+        && let Some(query) = req.uri().query()
+    {
+        target_url.push('?');
+        target_url.push_str(query);
     }
 
-    let body_bytes = match axum::body::to_bytes(req.into_body(), usize::MAX).await {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            tracing::error!("Failed to read request body for {}: {}", &target_url, e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let body_bytes = read_request_body(&state, req, &target_url).await?;
+
+    let (mut search_body, requested_rooms) = parse_search_body(&body_bytes)?;
+
+    // only rooms that are public right now are searched, whatever the
+    // appservice user has joined
+    let candidates = requested_rooms.unwrap_or_else(|| policy::search_candidates(&state));
+    let allowed_rooms = policy::filter_public(&state, candidates).await;
+
+    if allowed_rooms.is_empty() {
+        return json_response(StatusCode::OK, &empty_search_response());
+    }
+
+    search_body["search_categories"]["room_events"]["filter"]["rooms"] = json!(allowed_rooms);
+    let allowed_rooms: HashSet<String> = allowed_rooms.iter().map(|id| id.to_string()).collect();
+
+    let body_bytes = Bytes::from(serde_json::to_vec(&search_body).map_err(|e| {
+        tracing::error!("Failed to serialize search body: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?);
 
     let cache_key = if state.config.cache.search.enabled {
         let mut hasher = Sha256::new();
@@ -256,20 +671,18 @@ pub async fn matrix_proxy_search(
         String::new()
     };
 
-    if state.config.cache.search.enabled {
-        if let Ok(cached_response) = state.cache.get_cached_proxy_response(&cache_key).await {
-            tracing::info!("Returning cached search response for {}", target_url);
+    if state.config.cache.search.enabled
+        && let Ok(Some(cached_response)) = state
+            .cache
+            .get_cached_data::<CachedResponse>(&cache_key)
+            .await
+    {
+        tracing::info!("Returning cached search response for {}", target_url);
 
-            if let Ok(response) = Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "application/json")
-                .body(axum::body::Body::from(cached_response))
-                .map_err(|e| {
-                    tracing::error!("Failed to build response: {}", e);
-                })
-            {
-                return Ok(response);
-            }
+        // rooms can stop being public while the response is cached
+        let cached_response = filter_search_results(cached_response, &allowed_rooms);
+        if let Ok(response) = cached_response.into_response(false) {
+            return Ok(response);
         }
     }
 
@@ -299,22 +712,24 @@ pub async fn matrix_proxy_search(
 
     let status = response.status();
     let headers = response.headers().clone();
-    let body = response.bytes().await.map_err(|e| {
-        tracing::error!("Failed to read response body for {}: {}", target_url, e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let body = read_limited(response, state.config.server.max_response_body())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to read response body for {}: {}", target_url, e);
+            StatusCode::BAD_GATEWAY
+        })?;
 
-    let to_cache = body.to_vec();
-    let ttl = state.config.cache.search.ttl;
+    let to_cache = CachedResponse::new(status, &headers, body);
+    let ttl = to_cache.cache_ttl(
+        state.config.cache.search.ttl,
+        state.config.cache.negative_ttl,
+    );
 
-    if state.config.cache.search.enabled {
+    if state.config.cache.search.enabled && ttl > 0 {
+        let to_cache = to_cache.clone();
+        let target_url = target_url.clone();
         tokio::spawn(async move {
-            if (state
-                .cache
-                .cache_proxy_response(&cache_key, &to_cache, ttl)
-                .await)
-                .is_ok()
-            {
+            if (state.cache.cache_data(&cache_key, &to_cache, ttl).await).is_ok() {
                 tracing::info!("Cached proxied search response for {}", target_url);
             } else {
                 tracing::warn!("Failed to cache search response for {}", target_url);
@@ -322,22 +737,133 @@ pub async fn matrix_proxy_search(
         });
     }
 
-    let mut axum_response = Response::builder().status(status);
+    // the homeserver may still return events from rooms outside the filter
+    filter_search_results(to_cache, &allowed_rooms)
+        .into_response(false)
+        .map_err(|e| {
+            tracing::error!("Failed to build response for {}: {}", target_url, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
 
-    for (name, value) in headers.iter() {
-        if !is_hop_by_hop_header(name.as_str()) {
-            axum_response = axum_response.header(name, value);
-        }
+/// Largest `/search` body accepted, real requests are a few hundred bytes.
+const MAX_SEARCH_BODY: usize = 64 * 1024;
+const MAX_SEARCH_TERM: usize = 1024;
+
+/// Validates a `/search` body, keeping only the `room_events` category, and
+/// returns it with the rooms its filter asked for.
+fn parse_search_body(body: &[u8]) -> Result<(Value, Option<Vec<OwnedRoomId>>), StatusCode> {
+    if body.len() > MAX_SEARCH_BODY {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
     }
 
-    let response = axum_response
-        .body(axum::body::Body::from(body))
+    let body: Value = serde_json::from_slice(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let criteria = &body["search_categories"]["room_events"];
+    if !criteria.is_object() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    match criteria["search_term"].as_str() {
+        Some(term) if !term.trim().is_empty() && term.len() <= MAX_SEARCH_TERM => {}
+        _ => return Err(StatusCode::BAD_REQUEST),
+    }
+
+    let filter = &criteria["filter"];
+    if !filter.is_null() && !filter.is_object() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let requested_rooms = match &filter["rooms"] {
+        Value::Null => None,
+        Value::Array(rooms) => Some(
+            rooms
+                .iter()
+                .map(|room| {
+                    room.as_str()
+                        .and_then(|room| OwnedRoomId::try_from(room).ok())
+                        .ok_or(StatusCode::BAD_REQUEST)
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let search_body = json!({ "search_categories": { "room_events": criteria } });
+
+    Ok((search_body, requested_rooms))
+}
+
+fn empty_search_response() -> Value {
+    json!({
+        "search_categories": {
+            "room_events": { "count": 0, "highlights": [], "results": [] }
+        }
+    })
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Result<Response<Body>, StatusCode> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
         .map_err(|e| {
             tracing::error!("Failed to build response: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        })
+}
+
+/// Drops search results, groups and state for rooms that aren't in
+/// `allowed_rooms`. Responses that aren't successful JSON are left as they are.
+fn filter_search_results(
+    mut response: CachedResponse,
+    allowed_rooms: &HashSet<String>,
+) -> CachedResponse {
+    if !response.is_success() {
+        return response;
+    }
+    let Ok(mut body) = serde_json::from_slice::<Value>(&response.body) else {
+        return response;
+    };
+    let Some(room_events) = body.pointer_mut("/search_categories/room_events") else {
+        return response;
+    };
+
+    let is_allowed = |room_id: &str| allowed_rooms.contains(room_id);
+    let mut dropped = 0;
+
+    if let Some(results) = room_events.get_mut("results").and_then(Value::as_array_mut) {
+        let before = results.len();
+        results.retain(|result| result["result"]["room_id"].as_str().is_some_and(is_allowed));
+        dropped += before - results.len();
+
+        if dropped > 0
+            && let Some(count) = room_events.get_mut("count")
+            && let Some(total) = count.as_u64()
+        {
+            *count = json!(total.saturating_sub(dropped as u64));
+        }
+    }
+
+    for pointer in ["/state", "/groups/room_id"] {
+        if let Some(rooms) = room_events
+            .pointer_mut(pointer)
+            .and_then(Value::as_object_mut)
+        {
+            let before = rooms.len();
+            rooms.retain(|room_id, _| is_allowed(room_id));
+            dropped += before - rooms.len();
+        }
+    }
 
-    Ok(response)
+    if dropped > 0
+        && let Ok(filtered) = serde_json::to_vec(&body)
+    {
+        response.body = filtered;
+        // the validator no longer matches the body
+        response.headers.retain(|(name, _)| name != "etag");
+    }
+    response
 }
 
 fn is_hop_by_hop_header(name: &str) -> bool {
@@ -353,3 +879,154 @@ fn is_hop_by_hop_header(name: &str) -> bool {
             | "upgrade"
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_limited() {
+        let response = |body: &'static str| {
+            reqwest::Response::from(axum::http::Response::new(reqwest::Body::from(body)))
+        };
+
+        assert_eq!(
+            read_limited(response("hello"), 5).await.unwrap(),
+            b"hello".to_vec()
+        );
+        assert!(read_limited(response("hello!"), 5).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_search_body() {
+        let (body, rooms) = parse_search_body(
+            br#"{"search_categories": {"room_events": {"search_term": "hello", "filter": {"rooms": ["!a:test.local"]}}, "other": {}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rooms.unwrap(),
+            vec![OwnedRoomId::try_from("!a:test.local").unwrap()]
+        );
+        assert!(body["search_categories"].get("other").is_none());
+
+        let (_, rooms) =
+            parse_search_body(br#"{"search_categories": {"room_events": {"search_term": "hi"}}}"#)
+                .unwrap();
+        assert!(rooms.is_none());
+
+        for invalid in [
+            &br#"not json"#[..],
+            br#"{"search_categories": {}}"#,
+            br#"{"search_categories": {"room_events": {"search_term": " "}}}"#,
+            br#"{"search_categories": {"room_events": {"search_term": "hi", "filter": {"rooms": ["nope"]}}}}"#,
+        ] {
+            assert_eq!(parse_search_body(invalid).unwrap_err(), StatusCode::BAD_REQUEST);
+        }
+
+        let oversized = vec![b' '; MAX_SEARCH_BODY + 1];
+        assert_eq!(
+            parse_search_body(&oversized).unwrap_err(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn test_filter_search_results() {
+        let body = json!({
+            "search_categories": {
+                "room_events": {
+                    "count": 5,
+                    "results": [
+                        { "rank": 1.0, "result": { "room_id": "!a:test.local" } },
+                        { "rank": 0.5, "result": { "room_id": "!b:test.local" } },
+                    ],
+                    "groups": { "room_id": { "!a:test.local": {}, "!b:test.local": {} } },
+                }
+            }
+        });
+        let response = CachedResponse {
+            status: 200,
+            headers: vec![("etag".to_string(), "\"1\"".to_string())],
+            body: serde_json::to_vec(&body).unwrap(),
+        };
+        let allowed = HashSet::from(["!a:test.local".to_string()]);
+
+        let filtered = filter_search_results(response, &allowed);
+        let body: Value = serde_json::from_slice(&filtered.body).unwrap();
+        let room_events = &body["search_categories"]["room_events"];
+        assert_eq!(room_events["count"], 4);
+        assert_eq!(room_events["results"].as_array().unwrap().len(), 1);
+        assert!(
+            room_events["groups"]["room_id"]
+                .get("!b:test.local")
+                .is_none()
+        );
+        assert!(filtered.headers.is_empty());
+    }
+
+    #[test]
+    fn test_state_endpoint() {
+        assert_eq!(
+            StateEndpoint::from_path("/_matrix/client/v3/rooms/!a:test.local/state"),
+            Some(StateEndpoint::State)
+        );
+        assert_eq!(
+            StateEndpoint::from_path(
+                "/_matrix/client/v3/rooms/!a:test.local/state/m.room.server%5Facl/?format=event"
+            ),
+            Some(StateEndpoint::StateEvent(Some(
+                "m.room.server_acl".to_string()
+            )))
+        );
+        assert_eq!(
+            StateEndpoint::from_path("/_matrix/client/v3/rooms/!a:test.local/state/m.room%ZZ"),
+            Some(StateEndpoint::StateEvent(None))
+        );
+        assert_eq!(
+            StateEndpoint::from_path("/_matrix/client/v3/rooms/!a:test.local/messages"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_retain_state() {
+        let state_events = StateEvents {
+            allow: Vec::new(),
+            deny: vec!["m.room.server_acl".to_string()],
+        };
+        let mut body = json!({
+            "state": [
+                { "type": "m.room.name", "state_key": "" },
+                { "type": "m.room.server_acl", "state_key": "" },
+            ]
+        });
+
+        assert!(retain_state(&state_events, body.get_mut("state")));
+        assert_eq!(body["state"].as_array().unwrap().len(), 1);
+        assert!(!retain_state(&state_events, body.get_mut("state")));
+        assert!(!retain_state(&state_events, body.get_mut("missing")));
+    }
+
+    #[test]
+    fn test_cached_response_ttl() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("x-internal", "1".parse().unwrap());
+
+        let response = CachedResponse::new(StatusCode::OK, &headers, Vec::new());
+        assert_eq!(
+            response.headers,
+            vec![("content-type".to_string(), "application/json".to_string())]
+        );
+        assert_eq!(response.cache_ttl(3600, 30), 3600);
+
+        let not_found = CachedResponse::new(StatusCode::NOT_FOUND, &headers, Vec::new());
+        assert_eq!(not_found.cache_ttl(3600, 30), 30);
+
+        let limited = CachedResponse::new(StatusCode::TOO_MANY_REQUESTS, &headers, Vec::new());
+        assert_eq!(limited.cache_ttl(3600, 30), 0);
+
+        let failed = CachedResponse::new(StatusCode::BAD_GATEWAY, &headers, Vec::new());
+        assert_eq!(failed.cache_ttl(3600, 30), 0);
+    }
+}
@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::cache::{Cache, CacheError, unix_now};
+
+/// Cache key the lists are persisted under.
+pub const ROOM_LISTS_CACHE_KEY: &str = "room_lists";
+
+/// How often the lists are re-read from the cache, to pick up changes made
+/// through another instance.
+pub const ROOM_LISTS_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+// the lists are configuration, they shouldn't expire
+const ROOM_LISTS_TTL: u64 = 60 * 60 * 24 * 365 * 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomListKind {
+    Blocklist,
+    Allowlist,
+}
+
+/// A listed room, as given by the operator, with the room ID an alias
+/// resolved to when it was added.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomListEntry {
+    pub entry: String,
+    pub room_id: String,
+    pub added_at: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lists {
+    #[serde(default)]
+    pub blocklist: Vec<RoomListEntry>,
+    #[serde(default)]
+    pub allowlist: Vec<RoomListEntry>,
+}
+
+impl Lists {
+    fn list(&self, kind: RoomListKind) -> &Vec<RoomListEntry> {
+        match kind {
+            RoomListKind::Blocklist => &self.blocklist,
+            RoomListKind::Allowlist => &self.allowlist,
+        }
+    }
+
+    fn list_mut(&mut self, kind: RoomListKind) -> &mut Vec<RoomListEntry> {
+        match kind {
+            RoomListKind::Blocklist => &mut self.blocklist,
+            RoomListKind::Allowlist => &mut self.allowlist,
+        }
+    }
+}
+
+/// Runtime room blocklist and allowlist, managed through the admin API and
+/// kept in the cache backend so they survive restarts. Blocked rooms are
+/// never joined or served, and a non-empty allowlist limits the public rooms
+/// to the ones on it.
+#[derive(Debug, Clone, Default)]
+pub struct RoomLists {
+    lists: Arc<RwLock<Lists>>,
+    // held while reloading or updating, so a reload can't land between an
+    // update and its persist
+    sync: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl RoomLists {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Lists> {
+        self.lists.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Lists> {
+        self.lists.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub async fn load(&self, cache: &Cache) -> Result<(), CacheError> {
+        let _sync = self.sync.lock().await;
+        self.reload(cache).await
+    }
+
+    /// Applies a change on top of the persisted lists and persists the
+    /// result, so changes made through other instances aren't overwritten.
+    pub async fn update<T>(
+        &self,
+        cache: &Cache,
+        change: impl FnOnce(&Self) -> T,
+    ) -> Result<T, CacheError> {
+        let _sync = self.sync.lock().await;
+        self.reload(cache).await?;
+
+        let changed = change(self);
+
+        let lists = self.read().clone();
+        cache
+            .cache_data(ROOM_LISTS_CACHE_KEY, &lists, ROOM_LISTS_TTL)
+            .await?;
+
+        Ok(changed)
+    }
+
+    async fn reload(&self, cache: &Cache) -> Result<(), CacheError> {
+        let Some(lists) = cache.get_cached_data::<Lists>(ROOM_LISTS_CACHE_KEY).await? else {
+            return Ok(());
+        };
+
+        let mut current = self.write();
+        if *current != lists {
+            tracing::info!(
+                "Loaded room lists ({} blocked, {} allowed)",
+                lists.blocklist.len(),
+                lists.allowlist.len()
+            );
+            *current = lists;
+        }
+        Ok(())
+    }
+
+    pub fn lists(&self) -> Lists {
+        self.read().clone()
+    }
+
+    pub fn is_blocked(&self, room_id: &str) -> bool {
+        self.read()
+            .blocklist
+            .iter()
+            .any(|entry| entry.room_id == room_id)
+    }
+
+    /// Whether the allowlist lets a room through, every room passes an empty
+    /// one.
+    pub fn is_allowed(&self, room_id: &str) -> bool {
+        let lists = self.read();
+        lists.allowlist.is_empty() || lists.allowlist.iter().any(|entry| entry.room_id == room_id)
+    }
+
+    /// Adds an entry, replacing one for the same room or alias.
+    pub fn insert(&self, kind: RoomListKind, entry: String, room_id: String) -> RoomListEntry {
+        let entry = RoomListEntry {
+            entry,
+            room_id,
+            added_at: unix_now(),
+        };
+
+        let mut lists = self.write();
+        let list = lists.list_mut(kind);
+        list.retain(|listed| listed.entry != entry.entry && listed.room_id != entry.room_id);
+        list.push(entry.clone());
+
+        entry
+    }
+
+    /// Removes the entries matching a room ID or alias, returning them.
+    pub fn remove(&self, kind: RoomListKind, entry: &str) -> Vec<RoomListEntry> {
+        let mut lists = self.write();
+        let list = lists.list_mut(kind);
+
+        let (removed, kept) = std::mem::take(list)
+            .into_iter()
+            .partition(|listed| listed.entry == entry || listed.room_id == entry);
+        *list = kept;
+
+        removed
+    }
+
+    pub fn contains(&self, kind: RoomListKind, room_id: &str) -> bool {
+        self.read()
+            .list(kind)
+            .iter()
+            .any(|entry| entry.room_id == room_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_room_lists() {
+        let lists = RoomLists::new();
+        assert!(!lists.is_blocked("!a:test.local"));
+        assert!(lists.is_allowed("!a:test.local"));
+
+        lists.insert(
+            RoomListKind::Blocklist,
+            "#spam:test.local".to_string(),
+            "!a:test.local".to_string(),
+        );
+        assert!(lists.is_blocked("!a:test.local"));
+
+        lists.insert(
+            RoomListKind::Allowlist,
+            "!b:test.local".to_string(),
+            "!b:test.local".to_string(),
+        );
+        assert!(lists.is_allowed("!b:test.local"));
+        assert!(!lists.is_allowed("!c:test.local"));
+
+        // re-adding replaces the entry instead of duplicating it
+        lists.insert(
+            RoomListKind::Allowlist,
+            "!b:test.local".to_string(),
+            "!b:test.local".to_string(),
+        );
+        assert_eq!(lists.lists().allowlist.len(), 1);
+
+        let removed = lists.remove(RoomListKind::Blocklist, "#spam:test.local");
+        assert_eq!(removed.len(), 1);
+        assert!(!lists.is_blocked("!a:test.local"));
+
+        assert_eq!(
+            lists.remove(RoomListKind::Allowlist, "!b:test.local").len(),
+            1
+        );
+        assert!(lists.is_allowed("!c:test.local"));
+    }
+}
@@ -13,8 +13,9 @@ use ruma::{
             avatar::RoomAvatarEventContent,
             canonical_alias::RoomCanonicalAliasEventContent,
             create::RoomCreateEventContent,
+            guest_access::{GuestAccess, RoomGuestAccessEventContent},
             history_visibility::RoomHistoryVisibilityEventContent,
-            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            join_rules::RoomJoinRulesEventContent,
             name::RoomNameEventContent,
             topic::RoomTopicEventContent,
         },
@@ -22,13 +23,16 @@ use ruma::{
     },
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::{Value, json};
 
 use std::sync::Arc;
 
 use crate::AppState;
+use crate::admin::{JoinReason, record_join_reason};
 use crate::appservice::{JoinedRoomState, RoomSummary};
+use crate::config::Config;
+use crate::policy;
 
 use crate::middleware::Data;
 
@@ -38,8 +42,71 @@ use crate::error::AppserviceError;
 
 pub async fn public_rooms(
     State(state): State<Arc<AppState>>,
+    Query(params): Query<PublicRoomsParams>,
 ) -> Result<impl IntoResponse, AppserviceError> {
-    let rooms = if !state.config.cache.public_rooms.enabled {
+    let filter = RoomFilter::from_params(&params);
+    let rooms = load_public_rooms(&state).await?;
+
+    let page = paginate(filter.apply(rooms), params.since.as_deref(), params.limit)?;
+
+    Ok((StatusCode::OK, Json(page)))
+}
+
+/// `POST /publicRooms`, taking the filter body from the client-server spec.
+pub async fn public_rooms_filtered(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<PublicRoomsRequest>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    let filter = RoomFilter::from_request(&body);
+    let rooms = load_public_rooms(&state).await?;
+
+    let page = paginate(filter.apply(rooms), body.since.as_deref(), body.limit)?;
+
+    Ok((StatusCode::OK, Json(page)))
+}
+
+/// `GET /_matrix/client/v3/publicRooms`, the directory in the format Matrix
+/// clients expect.
+pub async fn matrix_public_rooms(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<MatrixPublicRoomsParams>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    check_directory_server(&state, params.server.as_deref())?;
+
+    let rooms = load_public_rooms(&state).await?;
+    let page = paginate(rooms, params.since.as_deref(), params.limit)?;
+
+    Ok((StatusCode::OK, Json(PublicRoomsResponse::from(page))))
+}
+
+/// `POST /_matrix/client/v3/publicRooms`.
+pub async fn matrix_public_rooms_filtered(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<PublicRoomsRequest>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    check_directory_server(&state, body.server.as_deref())?;
+
+    let filter = RoomFilter::from_request(&body);
+    let rooms = load_public_rooms(&state).await?;
+    let page = paginate(filter.apply(rooms), body.since.as_deref(), body.limit)?;
+
+    Ok((StatusCode::OK, Json(PublicRoomsResponse::from(page))))
+}
+
+/// Only our own directory is served, not other servers' over federation.
+fn check_directory_server(state: &AppState, server: Option<&str>) -> Result<(), AppserviceError> {
+    match server {
+        Some(server) if server != state.config.matrix.server_name => {
+            Err(AppserviceError::InvalidParam(format!("server: {server}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn load_public_rooms(state: &Arc<AppState>) -> Result<Vec<PublicRoom>, AppserviceError> {
+    let rooms = if state.index.is_ready() {
+        state.index.public_rooms(&state.config)
+    } else if !state.config.cache.public_rooms.enabled {
         tracing::info!("Public rooms cache is disabled, fetching directly from appservice");
         fetch_and_process_rooms(state.clone()).await
     } else {
@@ -75,10 +142,267 @@ pub async fn public_rooms(
         }
     };
 
-    Ok((StatusCode::OK, Json(json!({ "rooms": rooms }))))
+    // rooms joined before they or their server were blocked stay indexed
+    // until they're left
+    let rooms = rooms
+        .into_iter()
+        .filter(|room| {
+            RoomId::parse(room.room_id())
+                .is_ok_and(|room_id| policy::access_violation(state, &room_id).is_none())
+        })
+        .collect();
+
+    Ok(rooms)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PublicRoomsParams {
+    pub limit: Option<usize>,
+    pub since: Option<String>,
+    pub search: Option<String>,
+    /// Comma separated room types, `space` and `room` for spaces and rooms
+    /// without a type.
+    pub room_type: Option<String>,
+    pub is_bridge: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PublicRoomsRequest {
+    pub limit: Option<usize>,
+    pub since: Option<String>,
+    pub server: Option<String>,
+    #[serde(default)]
+    pub filter: PublicRoomsRequestFilter,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PublicRoomsRequestFilter {
+    pub generic_search_term: Option<String>,
+    /// `null` matches rooms without a type.
+    pub room_types: Option<Vec<Option<String>>>,
+    pub is_bridge: Option<bool>,
+}
+
+#[derive(Debug, Default)]
+struct RoomFilter {
+    search: Option<String>,
+    room_types: Option<Vec<Option<String>>>,
+    is_bridge: Option<bool>,
+}
+
+impl RoomFilter {
+    fn from_params(params: &PublicRoomsParams) -> Self {
+        let room_types = params.room_type.as_ref().map(|room_types| {
+            room_types
+                .split(',')
+                .map(str::trim)
+                .filter(|room_type| !room_type.is_empty())
+                .map(|room_type| match room_type {
+                    "room" => None,
+                    "space" => Some("m.space".to_string()),
+                    room_type => Some(room_type.to_string()),
+                })
+                .collect()
+        });
+
+        Self::new(params.search.as_deref(), room_types, params.is_bridge)
+    }
+
+    fn from_request(request: &PublicRoomsRequest) -> Self {
+        Self::new(
+            request.filter.generic_search_term.as_deref(),
+            request.filter.room_types.clone(),
+            request.filter.is_bridge,
+        )
+    }
+
+    fn new(
+        search: Option<&str>,
+        room_types: Option<Vec<Option<String>>>,
+        is_bridge: Option<bool>,
+    ) -> Self {
+        Self {
+            search: search
+                .map(str::trim)
+                .filter(|term| !term.is_empty())
+                .map(str::to_lowercase),
+            room_types,
+            is_bridge,
+        }
+    }
+
+    fn matches(&self, room: &PublicRoom) -> bool {
+        if let Some(is_bridge) = self.is_bridge
+            && room.is_bridge != is_bridge
+        {
+            return false;
+        }
+
+        // matches either the commune type or the m.room.create type, so
+        // spaces match `m.space` whatever their commune type
+        if let Some(room_types) = &self.room_types
+            && !room_types.contains(&room.room_type)
+            && !room_types.contains(&room.create_type)
+        {
+            return false;
+        }
+
+        match &self.search {
+            Some(term) => [
+                &room.name,
+                &room.topic,
+                &room.canonical_alias,
+                &room.commune_alias,
+            ]
+            .into_iter()
+            .flatten()
+            .any(|field| field.to_lowercase().contains(term)),
+            None => true,
+        }
+    }
+
+    fn apply(&self, rooms: Vec<PublicRoom>) -> Vec<PublicRoom> {
+        rooms
+            .into_iter()
+            .filter(|room| self.matches(room))
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct PublicRoomsPage {
+    #[serde(serialize_with = "serialize_listed_rooms")]
+    pub rooms: Vec<PublicRoom>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_batch: Option<String>,
+    pub total_room_count_estimate: usize,
+}
+
+/// Fields only the spec directory serves, they're kept with the rooms in the
+/// cache but left out of the `/publicRooms` listing.
+const DIRECTORY_ONLY_FIELDS: [&str; 2] = ["num_joined_members", "guest_can_join"];
+
+fn serialize_listed_rooms<S: Serializer>(
+    rooms: &[PublicRoom],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let rooms = rooms
+        .iter()
+        .map(|room| {
+            let mut room = serde_json::to_value(room).map_err(serde::ser::Error::custom)?;
+            if let Some(fields) = room.as_object_mut() {
+                for field in DIRECTORY_ONLY_FIELDS {
+                    fields.remove(field);
+                }
+            }
+            Ok(room)
+        })
+        .collect::<Result<Vec<Value>, S::Error>>()?;
+
+    rooms.serialize(serializer)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MatrixPublicRoomsParams {
+    pub limit: Option<usize>,
+    pub since: Option<String>,
+    pub server: Option<String>,
 }
 
-async fn fetch_and_process_rooms(state: Arc<AppState>) -> Vec<PublicRoom> {
+/// The client-server spec `/publicRooms` response.
+#[derive(Serialize)]
+pub struct PublicRoomsResponse {
+    pub chunk: Vec<PublicRoomsChunk>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_batch: Option<String>,
+    pub total_room_count_estimate: usize,
+}
+
+impl From<PublicRoomsPage> for PublicRoomsResponse {
+    fn from(page: PublicRoomsPage) -> Self {
+        Self {
+            chunk: page.rooms.into_iter().map(PublicRoomsChunk::from).collect(),
+            next_batch: page.next_batch,
+            prev_batch: page.prev_batch,
+            total_room_count_estimate: page.total_room_count_estimate,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PublicRoomsChunk {
+    pub room_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_alias: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub join_rule: Option<String>,
+    pub num_joined_members: u64,
+    pub world_readable: bool,
+    pub guest_can_join: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_type: Option<String>,
+}
+
+impl From<PublicRoom> for PublicRoomsChunk {
+    fn from(room: PublicRoom) -> Self {
+        Self {
+            world_readable: room.history_visibility == "world_readable",
+            room_id: room.room_id,
+            name: room.name,
+            topic: room.topic,
+            canonical_alias: room.canonical_alias,
+            avatar_url: room.avatar_url,
+            join_rule: room.join_rule,
+            num_joined_members: room.num_joined_members,
+            guest_can_join: room.guest_can_join,
+            // the spec room type is the m.room.create one
+            room_type: room.create_type,
+        }
+    }
+}
+
+/// Slices the filtered rooms into a page. Batch tokens are offsets into the
+/// list, which is in a stable order; without a limit the rest of the list is
+/// returned.
+fn paginate(
+    rooms: Vec<PublicRoom>,
+    since: Option<&str>,
+    limit: Option<usize>,
+) -> Result<PublicRoomsPage, AppserviceError> {
+    let total = rooms.len();
+
+    let start = match since {
+        Some(since) => since
+            .parse::<usize>()
+            .map_err(|_| AppserviceError::InvalidParam(format!("since: {since}")))?
+            .min(total),
+        None => 0,
+    };
+    let limit = limit.map(|limit| limit.max(1)).unwrap_or(total);
+    let end = start.saturating_add(limit).min(total);
+
+    let prev_batch = (start > 0).then(|| start.saturating_sub(limit).to_string());
+    let next_batch = (end < total).then(|| end.to_string());
+
+    Ok(PublicRoomsPage {
+        rooms: rooms.into_iter().skip(start).take(end - start).collect(),
+        next_batch,
+        prev_batch,
+        total_room_count_estimate: total,
+    })
+}
+
+pub(crate) async fn fetch_and_process_rooms(state: Arc<AppState>) -> Vec<PublicRoom> {
     match state.appservice.joined_rooms_state().await {
         Ok(Some(rooms)) => process_rooms(state, rooms),
         Ok(None) | Err(_) => Vec::new(),
@@ -117,222 +441,230 @@ pub struct PublicRoom {
     #[serde(default)]
     #[serde(skip_serializing_if = "is_false")]
     is_bridge: bool,
+    #[serde(default)]
+    pub(crate) num_joined_members: u64,
+    #[serde(default)]
+    guest_can_join: bool,
+    /// The `m.room.create` type, which `commune.room.type` overrides in
+    /// `type`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    create_type: Option<String>,
 }
 
 fn is_false(b: &bool) -> bool {
     !*b
 }
 
-fn process_rooms(_state: Arc<AppState>, rooms: Vec<JoinedRoomState>) -> Vec<PublicRoom> {
-    let mut public_rooms: Vec<PublicRoom> = Vec::new();
+const BRIDGE_TYPES: [&str; 5] = [
+    "m.bridge",
+    "m.room.bridged",
+    "m.room.discord",
+    "m.room.irc",
+    "uk.half-shot.bridge",
+];
+
+/// State event types that make up a [`PublicRoom`], besides the bridge
+/// markers.
+const PUBLIC_ROOM_STATE_TYPES: [&str; 12] = [
+    "m.room.create",
+    "m.room.name",
+    "commune.room.name",
+    "m.room.canonical_alias",
+    "m.room.avatar",
+    "m.room.topic",
+    "m.room.history_visibility",
+    "commune.room.banner",
+    "commune.room.type",
+    "m.room.join_rules",
+    "m.room.guest_access",
+    "m.space.child",
+];
+
+pub fn is_public_room_state(event_type: &str) -> bool {
+    PUBLIC_ROOM_STATE_TYPES.contains(&event_type) || BRIDGE_TYPES.contains(&event_type)
+}
+
+fn event_content<T: for<'de> Deserialize<'de>>(event: &Value) -> Option<T> {
+    serde_json::from_value(event.get("content")?.clone()).ok()
+}
 
-    for room in &rooms {
+impl PublicRoom {
+    /// Builds the directory entry for a room from its state events, in any
+    /// order.
+    pub fn from_state<'a>(room_id: &str, events: impl IntoIterator<Item = &'a Value>) -> Self {
         let mut pub_room = PublicRoom {
-            room_id: room.room_id.to_string(),
+            room_id: room_id.to_string(),
             ..Default::default()
         };
 
-        for state_event in &room.state.clone().unwrap_or(Vec::new()) {
-            let event_type = match state_event.get_field::<String>("type") {
-                Ok(Some(t)) => t,
-                Ok(None) => {
-                    continue;
-                }
-                Err(_) => {
-                    continue;
-                }
-            };
+        // commune.room.name and commune.room.type take precedence over the
+        // matrix equivalents
+        let mut commune_name = None;
+        let mut commune_type = None;
 
-            if event_type == "m.room.create" {
-
-                if let Ok(Some(osts)) = state_event.get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
-                {
-                    pub_room.origin_server_ts = Some(osts);
-                };
+        for state_event in events {
+            let Some(event_type) = state_event["type"].as_str() else {
+                continue;
+            };
 
-                if let Ok(Some(sender)) = state_event.get_field::<String>("sender")
-                {
-                    pub_room.sender = Some(sender.to_string());
-                };
+            match event_type {
+                "m.room.create" => {
+                    pub_room.origin_server_ts =
+                        serde_json::from_value(state_event["origin_server_ts"].clone()).ok();
+                    pub_room.sender = state_event["sender"].as_str().map(|s| s.to_string());
 
-                if let Ok(Some(content)) =
-                    state_event.get_field::<RoomCreateEventContent>("content")
-                {
-                    if let Some(room_type) = content.room_type {
+                    if let Some(content) = event_content::<RoomCreateEventContent>(state_event)
+                        && let Some(room_type) = content.room_type
+                    {
                         pub_room.room_type = Some(room_type.to_string());
+                        pub_room.create_type = Some(room_type.to_string());
                     }
-                };
-            }
-
-            // don't overwrite the name if commune.room.name is already set
-            if event_type == "m.room.name" && pub_room.name.is_none() {
-                if let Ok(Some(content)) = state_event.get_field::<RoomNameEventContent>("content")
-                {
-                    pub_room.name = Some(content.name.to_string());
-                };
-            }
-
-            if event_type == "commune.room.name" {
-                if let Ok(Some(content)) = state_event.get_field::<RoomNameEventContent>("content")
-                {
-                    pub_room.name = Some(content.name.to_string());
-                };
-            }
-
-            if event_type == "m.room.canonical_alias" {
-                if let Ok(Some(content)) =
-                    state_event.get_field::<RoomCanonicalAliasEventContent>("content")
-                {
-                    pub_room.canonical_alias = content.alias.map(|a| a.to_string());
-                };
-            }
-
-            if event_type == "m.room.avatar" {
-                if let Ok(Some(content)) =
-                    state_event.get_field::<RoomAvatarEventContent>("content")
-                {
-                    pub_room.avatar_url = content.url.map(|u| u.to_string());
-                };
-            }
-
-            if event_type == "m.room.topic" {
-                if let Ok(Some(content)) = state_event.get_field::<RoomTopicEventContent>("content")
-                {
-                    pub_room.topic = Some(content.topic.to_string());
-                };
-            }
-
-            if event_type == "m.room.history_visibility" {
-                if let Ok(Some(content)) =
-                    state_event.get_field::<RoomHistoryVisibilityEventContent>("content")
-                {
-                    pub_room.history_visibility = content.history_visibility.to_string();
-                };
-            }
-
-            if event_type == "commune.room.banner" {
-                if let Ok(Some(content)) =
-                    state_event.get_field::<RoomAvatarEventContent>("content")
-                {
-                    pub_room.banner_url = content.url.map(|u| u.to_string());
-                };
-            }
-
-            if event_type == "commune.room.type" {
-                if let Ok(Some(content)) = state_event.get_field::<CommuneRoomType>("content") {
-                    pub_room.room_type = content.room_type.map(|t| t.to_string());
-                };
-            }
-
-            if event_type == "m.room.join_rules" {
-                if let Ok(Some(content)) =
-                    state_event.get_field::<RoomJoinRulesEventContent>("content")
-                {
-                    pub_room.join_rule = Some(content.join_rule.as_str().to_string());
-                };
-            }
-
-            let bridge_types = [
-                "m.bridge",
-                "m.room.bridged",
-                "m.room.discord",
-                "m.room.irc",
-                "uk.half-shot.bridge",
-            ];
-
-            if bridge_types.contains(&event_type.as_str()) {
-                pub_room.is_bridge = true;
-            }
-
-            if event_type == "m.space.child" {
-                if let Ok(Some(content)) =
-                    state_event.get_field::<SpaceChildEventContent>("content")
-                {
+                }
+                "m.room.name" => {
+                    if let Some(content) = event_content::<RoomNameEventContent>(state_event) {
+                        pub_room.name = Some(content.name.to_string());
+                    }
+                }
+                "commune.room.name" => {
+                    if let Some(content) = event_content::<RoomNameEventContent>(state_event) {
+                        commune_name = Some(content.name.to_string());
+                    }
+                }
+                "m.room.canonical_alias" => {
+                    if let Some(content) =
+                        event_content::<RoomCanonicalAliasEventContent>(state_event)
+                    {
+                        pub_room.canonical_alias = content.alias.map(|a| a.to_string());
+                    }
+                }
+                "m.room.avatar" => {
+                    if let Some(content) = event_content::<RoomAvatarEventContent>(state_event) {
+                        pub_room.avatar_url = content.url.map(|u| u.to_string());
+                    }
+                }
+                "m.room.topic" => {
+                    if let Some(content) = event_content::<RoomTopicEventContent>(state_event) {
+                        pub_room.topic = Some(content.topic.to_string());
+                    }
+                }
+                "m.room.history_visibility" => {
+                    if let Some(content) =
+                        event_content::<RoomHistoryVisibilityEventContent>(state_event)
+                    {
+                        pub_room.history_visibility = content.history_visibility.to_string();
+                    }
+                }
+                "commune.room.banner" => {
+                    if let Some(content) = event_content::<RoomAvatarEventContent>(state_event) {
+                        pub_room.banner_url = content.url.map(|u| u.to_string());
+                    }
+                }
+                "commune.room.type" => {
+                    if let Some(content) = event_content::<CommuneRoomType>(state_event) {
+                        commune_type = Some(content.room_type);
+                    }
+                }
+                "m.room.join_rules" => {
+                    if let Some(content) = event_content::<RoomJoinRulesEventContent>(state_event) {
+                        pub_room.join_rule = Some(content.join_rule.as_str().to_string());
+                    }
+                }
+                "m.room.guest_access" => {
+                    if let Some(content) = event_content::<RoomGuestAccessEventContent>(state_event)
+                    {
+                        pub_room.guest_can_join = content.guest_access == GuestAccess::CanJoin;
+                    }
+                }
+                "m.room.member" if state_event["content"]["membership"] == "join" => {
+                    pub_room.num_joined_members += 1;
+                }
+                "m.space.child" => {
+                    // children without via servers have been removed
+                    let Some(content) = event_content::<SpaceChildEventContent>(state_event) else {
+                        continue;
+                    };
                     if content.via.is_empty() {
                         continue;
                     }
-                } else {
-                    continue;
-                };
-
-                if let Ok(Some(state_key)) = state_event.get_field::<String>("state_key") {
-                    let mut _is_public = false;
-
-                    // find the room in the rooms vec
-                    if let Some(child_room) = rooms.iter().find(|r| r.room_id == state_key) {
-                        for state_event in &child_room.state.clone().unwrap_or(Vec::new()) {
-                            let event_type = match state_event.get_field::<String>("type") {
-                                Ok(Some(t)) => t,
-                                Ok(None) => {
-                                    continue;
-                                }
-                                Err(_) => {
-                                    continue;
-                                }
-                            };
-
-                            if event_type == "m.room.join_rules" {
-                                if let Ok(Some(content)) =
-                                    state_event.get_field::<RoomJoinRulesEventContent>("content")
-                                {
-                                    if matches!(content.join_rule, JoinRule::Public) {
-                                        //is_public = true;
-                                        break;
-                                    }
-                                };
-                            }
-                        }
-                    }
 
-                    match pub_room.children {
-                        Some(ref mut children) => {
-                            children.push(state_key);
-                        }
-                        None => {
-                            pub_room.children = Some(vec![state_key]);
-                        }
+                    if let Some(state_key) = state_event["state_key"].as_str() {
+                        pub_room
+                            .children
+                            .get_or_insert_with(Vec::new)
+                            .push(state_key.to_string());
                     }
-                };
+                }
+                event_type if BRIDGE_TYPES.contains(&event_type) => {
+                    pub_room.is_bridge = true;
+                }
+                _ => {}
             }
         }
 
-        if let Some(name) = &pub_room.name {
-            if name.contains("[⛓️]") {
-                continue;
-            }
+        if commune_name.is_some() {
+            pub_room.name = commune_name;
+        }
+        if let Some(room_type) = commune_type {
+            pub_room.room_type = room_type;
         }
 
-        public_rooms.push(pub_room);
+        pub_room
     }
 
-    if _state.config.public_rooms.curated && !_state.config.public_rooms.include_rooms.is_empty() {
-        let mut include_rooms: Vec<String> = Vec::new();
+    pub fn room_id(&self) -> &str {
+        &self.room_id
+    }
 
-        for local_part in &_state.config.public_rooms.include_rooms {
-            let alias = format!("#{}:{}", local_part, _state.config.matrix.server_name);
-            include_rooms.push(alias);
-        }
+    /// Whether the room shows up in the directory at all.
+    pub fn is_listed(&self) -> bool {
+        !self.name.as_ref().is_some_and(|name| name.contains("[⛓️]"))
+    }
+}
 
-        // sort public_rooms by canonical_alias, comparing to the order or include_rooms
+fn process_rooms(state: Arc<AppState>, rooms: Vec<JoinedRoomState>) -> Vec<PublicRoom> {
+    let mut public_rooms: Vec<PublicRoom> = rooms
+        .iter()
+        .map(|room| {
+            let events: Vec<Value> = room
+                .state
+                .iter()
+                .flatten()
+                .filter_map(|event| serde_json::from_str(event.json().get()).ok())
+                .collect();
 
-        public_rooms.sort_by(|a, b| {
-            let a_alias = a.canonical_alias.clone().unwrap_or_default();
-            let b_alias = b.canonical_alias.clone().unwrap_or_default();
+            PublicRoom::from_state(room.room_id.as_str(), &events)
+        })
+        .filter(PublicRoom::is_listed)
+        .collect();
 
-            let a_index = include_rooms
-                .iter()
-                .position(|x| x == &a_alias)
-                .unwrap_or(usize::MAX);
-            let b_index = include_rooms
-                .iter()
-                .position(|x| x == &b_alias)
-                .unwrap_or(usize::MAX);
+    // the same stable order as the room index, so offsets stay valid across pages
+    public_rooms.sort_by(|a, b| a.room_id().cmp(b.room_id()));
+    sort_curated(&state.config, &mut public_rooms);
 
-            a_index.cmp(&b_index)
-        });
+    public_rooms
+}
+
+/// Orders curated rooms by their position in `include_rooms`.
+pub(crate) fn sort_curated(config: &Config, public_rooms: &mut [PublicRoom]) {
+    if !config.public_rooms.curated || config.public_rooms.include_rooms.is_empty() {
+        return;
     }
 
-    public_rooms
+    let include_rooms: Vec<String> = config
+        .public_rooms
+        .include_rooms
+        .iter()
+        .map(|local_part| format!("#{}:{}", local_part, config.matrix.server_name))
+        .collect();
+
+    public_rooms.sort_by_key(|room| {
+        let alias = room.canonical_alias.clone().unwrap_or_default();
+        include_rooms
+            .iter()
+            .position(|x| x == &alias)
+            .unwrap_or(usize::MAX)
+    });
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -462,12 +794,18 @@ pub async fn room_info(
                 AppserviceError::MatrixError("Failed to fetch sender profile".to_string())
             })?;
 
-            let avatar_url = profile.get("avatar_url").and_then(|v| v.as_str()).map(|s| s.to_string());
-            let displayname = profile.get("displayname").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let avatar_url = profile
+                .get("avatar_url")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string());
+            let displayname = profile
+                .get("displayname")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string());
 
             info.sender = Some(Sender {
-                avatar_url: avatar_url,
-                displayname: displayname,
+                avatar_url,
+                displayname,
             });
         }
     }
@@ -486,6 +824,14 @@ pub async fn join_room(
         AppserviceError::MatrixError(format!("Invalid room ID: {e}"))
     })?;
 
+    // the same checks automatic joins go through
+    if let Some(violation) = policy::access_violation(&state, &room_id) {
+        tracing::info!("Not joining room {}: {:?}", room_id, violation);
+        return Err(AppserviceError::AppserviceError(format!(
+            "Room cannot be joined: {violation:?}"
+        )));
+    }
+
     if let Err(e) = state.appservice.join_room(&room_id).await {
         tracing::error!("Failed to join room {}: {}", room_id, e);
         return Err(AppserviceError::MatrixError(format!(
@@ -493,6 +839,8 @@ pub async fn join_room(
         )));
     }
 
+    record_join_reason(&state, &room_id, JoinReason::Admin).await;
+
     Ok((
         StatusCode::OK,
         Json(json!({
@@ -526,3 +874,88 @@ pub async fn leave_room(
         })),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(room_id: &str, name: &str, room_type: Option<&str>, is_bridge: bool) -> PublicRoom {
+        PublicRoom {
+            room_id: room_id.to_string(),
+            name: Some(name.to_string()),
+            room_type: room_type.map(str::to_string),
+            create_type: room_type.map(str::to_string),
+            is_bridge,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_room_filter() {
+        let rooms = vec![
+            room("!a:test.local", "Art", Some("m.space"), false),
+            room("!b:test.local", "Book club", None, false),
+            room("!c:test.local", "Art chat", None, true),
+        ];
+
+        let params = PublicRoomsParams {
+            search: Some(" ART ".to_string()),
+            room_type: Some("room".to_string()),
+            ..Default::default()
+        };
+        let filtered = RoomFilter::from_params(&params).apply(rooms.clone());
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].room_id(), "!c:test.local");
+
+        let request: PublicRoomsRequest = serde_json::from_value(json!({
+            "filter": { "room_types": ["m.space", null], "is_bridge": false }
+        }))
+        .unwrap();
+        let filtered = RoomFilter::from_request(&request).apply(rooms);
+        let ids: Vec<&str> = filtered.iter().map(PublicRoom::room_id).collect();
+        assert_eq!(ids, ["!a:test.local", "!b:test.local"]);
+    }
+
+    #[test]
+    fn test_public_rooms_chunk() {
+        let mut space = room("!a:test.local", "Art", Some("m.space"), false);
+        space.room_type = Some("forum".to_string());
+        space.history_visibility = "world_readable".to_string();
+        space.num_joined_members = 3;
+
+        let chunk = serde_json::to_value(PublicRoomsChunk::from(space)).unwrap();
+        assert_eq!(chunk["room_type"], "m.space");
+        assert_eq!(chunk["world_readable"], true);
+        assert_eq!(chunk["guest_can_join"], false);
+        assert_eq!(chunk["num_joined_members"], 3);
+
+        // the bespoke listing keeps its original shape
+        let mut room = room("!b:test.local", "Books", None, false);
+        room.num_joined_members = 3;
+        let page = serde_json::to_value(paginate(vec![room], None, None).unwrap()).unwrap();
+        assert_eq!(page["rooms"][0]["name"], "Books");
+        assert!(page["rooms"][0].get("num_joined_members").is_none());
+        assert!(page["rooms"][0].get("guest_can_join").is_none());
+    }
+
+    #[test]
+    fn test_paginate() {
+        let rooms: Vec<PublicRoom> = (0..5)
+            .map(|i| room(&format!("!{i}:test.local"), "Room", None, false))
+            .collect();
+
+        let page = paginate(rooms.clone(), None, Some(2)).unwrap();
+        assert_eq!(page.rooms.len(), 2);
+        assert_eq!(page.next_batch.as_deref(), Some("2"));
+        assert!(page.prev_batch.is_none());
+        assert_eq!(page.total_room_count_estimate, 5);
+
+        let page = paginate(rooms.clone(), Some("4"), Some(2)).unwrap();
+        assert_eq!(page.rooms[0].room_id(), "!4:test.local");
+        assert!(page.next_batch.is_none());
+        assert_eq!(page.prev_batch.as_deref(), Some("2"));
+
+        assert_eq!(paginate(rooms.clone(), None, None).unwrap().rooms.len(), 5);
+        assert!(paginate(rooms, Some("nope"), None).is_err());
+    }
+}
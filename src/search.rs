@@ -0,0 +1,525 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    response::IntoResponse,
+};
+
+use futures::StreamExt;
+
+use ruma::OwnedRoomId;
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use crate::AppState;
+use crate::config::Config;
+use crate::error::AppserviceError;
+use crate::policy;
+
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+/// Rooms backfilled at the same time when the index is built.
+const BACKFILL_CONCURRENCY: usize = 4;
+
+/// Splits text into lowercase alphanumeric terms.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[derive(Debug)]
+struct Document {
+    event: Value,
+    room_id: String,
+    sender: String,
+    origin_server_ts: u64,
+    terms: HashMap<String, u32>,
+    len: u32,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // ordered by insertion, so the oldest messages are dropped first
+    docs: BTreeMap<u64, Document>,
+    event_ids: HashMap<String, u64>,
+    postings: HashMap<String, HashMap<u64, u32>>,
+    total_len: u64,
+    next_id: u64,
+}
+
+impl Inner {
+    fn insert(&mut self, event_id: String, document: Document) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        for (term, count) in &document.terms {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(id, *count);
+        }
+        self.total_len += u64::from(document.len);
+        self.event_ids.insert(event_id, id);
+        self.docs.insert(id, document);
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Document> {
+        let document = self.docs.remove(&id)?;
+
+        for term in document.terms.keys() {
+            if let Some(postings) = self.postings.get_mut(term) {
+                postings.remove(&id);
+                if postings.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        self.total_len -= u64::from(document.len);
+        if let Some(event_id) = document.event["event_id"].as_str() {
+            self.event_ids.remove(event_id);
+        }
+
+        Some(document)
+    }
+}
+
+/// In-memory full-text index of public room messages, ranked with BM25.
+/// It's fed from transactions and a backfill at startup, so `/search` doesn't
+/// depend on the homeserver's search backend.
+#[derive(Debug, Clone)]
+pub struct SearchIndex {
+    inner: Arc<RwLock<Inner>>,
+    max_documents: usize,
+    backfill_limit: usize,
+}
+
+impl SearchIndex {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Inner::default())),
+            max_documents: config.search.max_documents,
+            backfill_limit: config.search.backfill_limit,
+        }
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Inner> {
+        self.inner.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Inner> {
+        self.inner.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().docs.is_empty()
+    }
+
+    /// Indexes an `m.room.message` event. Returns whether it was added.
+    pub fn index_event(&self, event: &Value) -> bool {
+        if event["type"] != "m.room.message" {
+            return false;
+        }
+
+        let (Some(event_id), Some(room_id), Some(body)) = (
+            event["event_id"].as_str(),
+            event["room_id"].as_str(),
+            event["content"]["body"].as_str(),
+        ) else {
+            return false;
+        };
+
+        let mut terms: HashMap<String, u32> = HashMap::new();
+        let mut len = 0;
+        for term in tokenize(body) {
+            *terms.entry(term).or_default() += 1;
+            len += 1;
+        }
+        if terms.is_empty() {
+            return false;
+        }
+
+        let document = Document {
+            event: event.clone(),
+            room_id: room_id.to_string(),
+            sender: event["sender"].as_str().unwrap_or_default().to_string(),
+            origin_server_ts: event["origin_server_ts"].as_u64().unwrap_or_default(),
+            terms,
+            len,
+        };
+
+        let mut inner = self.write();
+        if inner.event_ids.contains_key(event_id) {
+            return false;
+        }
+        inner.insert(event_id.to_string(), document);
+
+        while inner.docs.len() > self.max_documents {
+            let Some(oldest) = inner.docs.keys().next().copied() else {
+                break;
+            };
+            inner.remove(oldest);
+        }
+
+        true
+    }
+
+    /// Drops a redacted message.
+    pub fn remove_event(&self, event_id: &str) -> bool {
+        let mut inner = self.write();
+        match inner.event_ids.get(event_id).copied() {
+            Some(id) => inner.remove(id).is_some(),
+            None => false,
+        }
+    }
+
+    /// Drops every message from a room the appservice left.
+    pub fn remove_room(&self, room_id: &str) -> usize {
+        let mut inner = self.write();
+        let ids: Vec<u64> = inner
+            .docs
+            .iter()
+            .filter(|(_, document)| document.room_id == room_id)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in &ids {
+            inner.remove(*id);
+        }
+        ids.len()
+    }
+
+    /// Indexes recent messages from every joined room.
+    pub async fn backfill(&self, state: &AppState) {
+        let rooms = match state.appservice.joined_rooms().await {
+            Ok(rooms) => rooms,
+            Err(e) => {
+                tracing::error!("Failed to backfill search index: {}", e);
+                return;
+            }
+        };
+
+        futures::stream::iter(rooms)
+            .for_each_concurrent(BACKFILL_CONCURRENCY, |room_id| async move {
+                if let Err(e) = self.backfill_room(state, room_id.clone()).await {
+                    tracing::warn!("Failed to backfill search index for {}: {}", room_id, e);
+                }
+            })
+            .await;
+
+        tracing::info!("Backfilled search index ({} messages)", self.len());
+    }
+
+    async fn backfill_room(
+        &self,
+        state: &AppState,
+        room_id: OwnedRoomId,
+    ) -> Result<(), anyhow::Error> {
+        let mut from = None;
+        let mut fetched = 0;
+
+        while fetched < self.backfill_limit {
+            let messages = state
+                .appservice
+                .get_room_messages(room_id.clone(), from)
+                .await?;
+
+            if messages.chunk.is_empty() {
+                break;
+            }
+            fetched += messages.chunk.len();
+
+            for event in &messages.chunk {
+                if let Ok(event) = serde_json::from_str::<Value>(event.json().get()) {
+                    self.index_event(&event);
+                }
+            }
+
+            match messages.end {
+                Some(end) => from = Some(end),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn search(&self, criteria: &RoomEventsCriteria, next_batch: Option<&str>) -> Value {
+        let terms: HashSet<String> = tokenize(&criteria.search_term).collect();
+        let filter = &criteria.filter;
+
+        // only the message body is indexed
+        let searches_body = criteria
+            .keys
+            .as_ref()
+            .is_none_or(|keys| keys.iter().any(|key| key == "content.body"));
+
+        let inner = self.read();
+
+        let mut scores: HashMap<u64, f64> = HashMap::new();
+        if searches_body && !inner.docs.is_empty() {
+            let total = inner.docs.len() as f64;
+            let avg_len = inner.total_len as f64 / total;
+
+            for term in &terms {
+                let Some(postings) = inner.postings.get(term) else {
+                    continue;
+                };
+                let df = postings.len() as f64;
+                let idf = ((total - df + 0.5) / (df + 0.5) + 1.0).ln();
+
+                for (id, tf) in postings {
+                    let document = &inner.docs[id];
+                    if !filter.matches(document) {
+                        continue;
+                    }
+
+                    let tf = f64::from(*tf);
+                    let norm = 1.0 - BM25_B + BM25_B * f64::from(document.len) / avg_len;
+                    *scores.entry(*id).or_default() +=
+                        idf * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * norm);
+                }
+            }
+        }
+
+        let mut ranked: Vec<(u64, f64)> = scores.into_iter().collect();
+        match criteria.order_by {
+            OrderBy::Rank => ranked.sort_by(|a, b| {
+                b.1.total_cmp(&a.1).then_with(|| {
+                    inner.docs[&b.0]
+                        .origin_server_ts
+                        .cmp(&inner.docs[&a.0].origin_server_ts)
+                })
+            }),
+            OrderBy::Recent => ranked.sort_by(|a, b| {
+                inner.docs[&b.0]
+                    .origin_server_ts
+                    .cmp(&inner.docs[&a.0].origin_server_ts)
+                    .then_with(|| b.1.total_cmp(&a.1))
+            }),
+        }
+
+        let count = ranked.len();
+        let start = next_batch
+            .and_then(|batch| batch.parse::<usize>().ok())
+            .unwrap_or(0)
+            .min(count);
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let end = start.saturating_add(limit).min(count);
+
+        let mut highlights = HashSet::new();
+        let results: Vec<Value> = ranked[start..end]
+            .iter()
+            .map(|(id, rank)| {
+                let document = &inner.docs[id];
+                highlights.extend(
+                    terms
+                        .iter()
+                        .filter(|term| document.terms.contains_key(*term))
+                        .cloned(),
+                );
+                json!({ "rank": rank, "result": document.event })
+            })
+            .collect();
+
+        let mut highlights: Vec<String> = highlights.into_iter().collect();
+        highlights.sort();
+
+        let mut room_events = json!({
+            "count": count,
+            "highlights": highlights,
+            "results": results,
+        });
+        if end < count {
+            room_events["next_batch"] = json!(end.to_string());
+        }
+
+        json!({ "search_categories": { "room_events": room_events } })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub next_batch: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchRequest {
+    pub search_categories: SearchCategories,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchCategories {
+    pub room_events: Option<RoomEventsCriteria>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomEventsCriteria {
+    pub search_term: String,
+    pub keys: Option<Vec<String>>,
+    #[serde(default)]
+    pub filter: RoomEventFilter,
+    #[serde(default)]
+    pub order_by: OrderBy,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RoomEventFilter {
+    pub limit: Option<usize>,
+    pub rooms: Option<Vec<String>>,
+    #[serde(default)]
+    pub not_rooms: Vec<String>,
+    pub senders: Option<Vec<String>>,
+    #[serde(default)]
+    pub not_senders: Vec<String>,
+}
+
+impl RoomEventFilter {
+    fn matches(&self, document: &Document) -> bool {
+        self.rooms
+            .as_ref()
+            .is_none_or(|rooms| rooms.contains(&document.room_id))
+            && !self.not_rooms.contains(&document.room_id)
+            && self
+                .senders
+                .as_ref()
+                .is_none_or(|senders| senders.contains(&document.sender))
+            && !self.not_senders.contains(&document.sender)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderBy {
+    #[default]
+    Rank,
+    Recent,
+}
+
+/// `POST /_matrix/client/v3/search` answered from the local index.
+pub async fn search(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SearchParams>,
+    Json(request): Json<SearchRequest>,
+) -> Result<impl IntoResponse, AppserviceError> {
+    let Some(index) = state.search.as_ref() else {
+        return Err(AppserviceError::AppserviceError(
+            "Search index is not enabled".to_string(),
+        ));
+    };
+
+    let Some(mut criteria) = request.search_categories.room_events else {
+        return Ok(Json(json!({ "search_categories": {} })));
+    };
+
+    // messages stay indexed after a room stops being public, so results are
+    // limited to rooms that pass the policy now
+    let candidates = match criteria.filter.rooms.take() {
+        Some(rooms) => rooms
+            .into_iter()
+            .map(|room| {
+                OwnedRoomId::try_from(room.as_str())
+                    .map_err(|_| AppserviceError::InvalidParam(format!("filter.rooms: {room}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => policy::search_candidates(&state),
+    };
+    let allowed_rooms = policy::filter_public(&state, candidates).await;
+    criteria.filter.rooms = Some(allowed_rooms.iter().map(|id| id.to_string()).collect());
+
+    Ok(Json(index.search(&criteria, params.next_batch.as_deref())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(max_documents: usize) -> SearchIndex {
+        SearchIndex {
+            inner: Arc::new(RwLock::new(Inner::default())),
+            max_documents,
+            backfill_limit: 0,
+        }
+    }
+
+    fn message(event_id: &str, room_id: &str, ts: u64, body: &str) -> Value {
+        json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "room_id": room_id,
+            "sender": "@alice:test.local",
+            "origin_server_ts": ts,
+            "content": { "msgtype": "m.text", "body": body },
+        })
+    }
+
+    fn criteria(value: Value) -> RoomEventsCriteria {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_search_ranks_and_filters() {
+        let index = index(10);
+        index.index_event(&message(
+            "$1",
+            "!a:test.local",
+            1,
+            "Rust rust and more rust",
+        ));
+        index.index_event(&message(
+            "$2",
+            "!a:test.local",
+            2,
+            "I like Rust, and tea and coffee",
+        ));
+        index.index_event(&message("$3", "!b:test.local", 3, "rust"));
+        index.index_event(&message("$4", "!b:test.local", 4, "nothing to see"));
+
+        let response = index.search(&criteria(json!({ "search_term": "RUST" })), None);
+        let room_events = &response["search_categories"]["room_events"];
+        assert_eq!(room_events["count"], 3);
+        assert_eq!(room_events["highlights"], json!(["rust"]));
+        assert_eq!(room_events["results"][0]["result"]["event_id"], "$1");
+
+        let response = index.search(
+            &criteria(json!({
+                "search_term": "rust",
+                "order_by": "recent",
+                "filter": { "rooms": ["!a:test.local"], "limit": 1 },
+            })),
+            None,
+        );
+        let room_events = &response["search_categories"]["room_events"];
+        assert_eq!(room_events["count"], 2);
+        assert_eq!(room_events["results"][0]["result"]["event_id"], "$2");
+        assert_eq!(room_events["next_batch"], "1");
+    }
+
+    #[test]
+    fn test_search_index_removal() {
+        let index = index(2);
+        index.index_event(&message("$1", "!a:test.local", 1, "first"));
+        index.index_event(&message("$2", "!a:test.local", 2, "second"));
+        index.index_event(&message("$3", "!b:test.local", 3, "third"));
+
+        // the oldest message made room for the newest
+        assert_eq!(index.len(), 2);
+        assert!(!index.remove_event("$1"));
+
+        assert!(index.remove_event("$2"));
+        assert_eq!(index.remove_room("!b:test.local"), 1);
+        assert!(index.is_empty());
+        assert!(index.read().postings.is_empty());
+    }
+}
@@ -4,7 +4,7 @@ use axum::{
     http::HeaderValue,
     middleware::{self},
     response::IntoResponse,
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
 };
 
 use std::sync::Arc;
@@ -19,19 +19,26 @@ use serde_json::json;
 
 use http::header::CONTENT_TYPE;
 
+use crate::admin;
 use crate::error::AppserviceError;
+use crate::index::INDEX_FLUSH_INTERVAL;
+use crate::roomlist::ROOM_LISTS_RELOAD_INTERVAL;
 use anyhow;
 
-use crate::config::Config;
+use crate::config::{Config, SearchBackend};
 use crate::middleware::{
     add_data, authenticate_homeserver, is_admin, validate_public_room, validate_room_id,
 };
-use crate::rooms::{join_room, leave_room, public_rooms, room_info};
+use crate::rooms::{
+    join_room, leave_room, matrix_public_rooms, matrix_public_rooms_filtered, public_rooms,
+    public_rooms_filtered, room_info,
+};
 
 use crate::ping::ping;
 
 use crate::api::transactions;
 use crate::requests::{matrix_proxy, matrix_proxy_search};
+use crate::search;
 
 use crate::space::{space, space_rooms, spaces};
 
@@ -129,7 +136,15 @@ impl Server {
                 validate_room_id,
             ));
 
-        let public_rooms_route = Router::new().route("/publicRooms", get(public_rooms));
+        let public_rooms_route = Router::new()
+            .route(
+                "/publicRooms",
+                get(public_rooms).post(public_rooms_filtered),
+            )
+            .route(
+                "/_matrix/client/v3/publicRooms",
+                get(matrix_public_rooms).post(matrix_public_rooms_filtered),
+            );
 
         let media_routes = Router::new()
             .route("/_matrix/client/v1/media/preview_url", get(matrix_proxy))
@@ -143,8 +158,30 @@ impl Server {
             );
 
         let admin_routes = Router::new()
+            .route("/admin/rooms", get(admin::rooms))
+            .route("/admin/room/{room_id}", get(admin::room))
             .route("/admin/room/{room_id}/join", put(join_room))
             .route("/admin/room/{room_id}/leave", put(leave_room))
+            .route("/admin/cache/stats", get(admin::cache_stats))
+            .route("/admin/cache/keys", get(admin::cache_keys))
+            .route(
+                "/admin/cache/room/{room_id}",
+                delete(admin::purge_room_cache),
+            )
+            .route(
+                "/admin/cache/category/{category}",
+                delete(admin::flush_cache_category),
+            )
+            .route(
+                "/admin/cache/refresh/public_rooms",
+                post(admin::refresh_public_rooms),
+            )
+            .route("/admin/cache/refresh/spaces", post(admin::refresh_spaces))
+            .route("/admin/room_lists", get(admin::room_lists))
+            .route(
+                "/admin/room_lists/{kind}/{entry}",
+                put(admin::add_room_list_entry).delete(admin::remove_room_list_entry),
+            )
             .route_layer(middleware::from_fn_with_state(self.state.clone(), is_admin));
 
         let spaces_routes = Router::new()
@@ -152,8 +189,14 @@ impl Server {
             .route("/spaces/{space}", get(space))
             .route("/spaces", get(spaces));
 
-        let search_route =
-            Router::new().route("/_matrix/client/v3/search", post(matrix_proxy_search));
+        let search_route = match self.state.config.search.backend {
+            SearchBackend::Homeserver => {
+                Router::new().route("/_matrix/client/v3/search", post(matrix_proxy_search))
+            }
+            SearchBackend::Local => {
+                Router::new().route("/_matrix/client/v3/search", post(search::search))
+            }
+        };
 
         let app = Router::new()
             .merge(service_routes)
@@ -161,9 +204,17 @@ impl Server {
             .merge(more_room_routes)
             .merge(media_routes)
             .merge(public_rooms_route)
-            .merge(admin_routes)
             .merge(spaces_routes);
 
+        let app = if self.state.config.admin.enabled {
+            if self.state.config.admin.tokens.is_empty() {
+                tracing::warn!("Admin routes are enabled but no admin tokens are configured");
+            }
+            app.merge(admin_routes)
+        } else {
+            app
+        };
+
         let app = if !self.state.config.search.disabled {
             app.merge(search_route)
         } else {
@@ -192,6 +243,51 @@ impl Server {
             }
         });
 
+        let index_state = self.state.clone();
+        tokio::spawn(async move {
+            info!("Loading public room index...");
+            index_state.index.load_or_build(&index_state).await;
+
+            let mut interval = tokio::time::interval(INDEX_FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                index_state.index.flush(&index_state).await;
+            }
+        });
+
+        let room_lists_state = self.state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ROOM_LISTS_RELOAD_INTERVAL);
+            // the lists were loaded at startup
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = room_lists_state
+                    .room_lists
+                    .load(&room_lists_state.cache)
+                    .await
+                {
+                    tracing::warn!("Failed to reload room lists: {}", e);
+                }
+            }
+        });
+
+        if !self.state.config.moderation.policy_rooms.is_empty() {
+            let moderation_state = self.state.clone();
+            tokio::spawn(async move {
+                info!("Loading moderation policy lists...");
+                moderation_state.moderation.load(&moderation_state).await;
+            });
+        }
+
+        if let Some(search) = self.state.search.clone() {
+            let search_state = self.state.clone();
+            tokio::spawn(async move {
+                info!("Backfilling search index...");
+                search.backfill(&search_state).await;
+            });
+        }
+
         if let Ok(listener) = tokio::net::TcpListener::bind(addr.clone()).await {
             axum::serve(listener, ServiceExt::<Request>::into_make_service(app)).await?;
         } else {
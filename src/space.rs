@@ -14,7 +14,7 @@ use std::sync::Arc;
 
 use crate::appservice::RoomSummary;
 
-use crate::cache::CacheKey;
+use crate::cache::{CacheError, CacheKey};
 
 pub async fn spaces(
     State(state): State<Arc<AppState>>,
@@ -46,14 +46,13 @@ pub async fn spaces(
         .cache
         .get_cached_data::<Vec<RoomSummary>>("public_spaces")
         .await
+        && !cached_spaces.is_empty()
     {
-        if !cached_spaces.is_empty() {
-            tracing::info!(
-                "Returning cached public spaces ({} spaces)",
-                cached_spaces.len()
-            );
-            return Ok(Json(json!(cached_spaces)));
-        }
+        tracing::info!(
+            "Returning cached public spaces ({} spaces)",
+            cached_spaces.len()
+        );
+        return Ok(Json(json!(cached_spaces)));
     }
 
     // cache missed
@@ -64,7 +63,7 @@ pub async fn spaces(
 
             let public_spaces = state.appservice.get_public_spaces().await.map_err(|e| {
                 tracing::error!("Failed to get public spaces: {}", e);
-                redis::RedisError::from((redis::ErrorKind::IoError, "Failed to get public spaces"))
+                CacheError::Fetch("Failed to get public spaces".to_string())
             })?;
 
             match public_spaces {
@@ -74,10 +73,7 @@ pub async fn spaces(
                 }
                 None => {
                     tracing::warn!("No public spaces found");
-                    Err(redis::RedisError::from((
-                        redis::ErrorKind::ResponseError,
-                        "No public spaces found",
-                    )))
+                    Err(CacheError::Fetch("No public spaces found".to_string()))
                 }
             }
         })
@@ -146,7 +142,7 @@ pub async fn space(
                 .await
                 .map_err(|e| {
                     tracing::error!("Failed to get room ID from alias: {}", e);
-                    redis::RedisError::from((redis::ErrorKind::IoError, "Space does not exist"))
+                    CacheError::Fetch("Space does not exist".to_string())
                 })?;
 
             let summary = state
@@ -155,10 +151,7 @@ pub async fn space(
                 .await
                 .map_err(|e| {
                     tracing::error!("Failed to get room summary: {}", e);
-                    redis::RedisError::from((
-                        redis::ErrorKind::IoError,
-                        "Failed to get space summary",
-                    ))
+                    CacheError::Fetch("Failed to get space summary".to_string())
                 })?;
 
             tracing::info!("Fetched and cached space summary for {}", space);
@@ -225,10 +218,7 @@ pub async fn space_rooms(
                     .await
                     .map_err(|e| {
                         tracing::error!("Failed to get space hierarchy: {}", e);
-                        redis::RedisError::from((
-                            redis::ErrorKind::IoError,
-                            "Failed to get space hierarchy",
-                        ))
+                        CacheError::Fetch("Failed to get space hierarchy".to_string())
                     })?;
 
                 tracing::info!(
@@ -0,0 +1,39 @@
+//! Fixtures shared by the unit tests.
+
+use serde_json::{Value, json};
+
+use crate::config::Config;
+
+/// A minimal config with the given `[appservice.rules]` entries.
+pub(crate) fn config_with_rules(rules: &str) -> Config {
+    toml::from_str(&format!(
+        r#"
+            [appservice]
+            id = "test"
+            sender_localpart = "public"
+            access_token = "token"
+            hs_access_token = "hs_token"
+
+            [appservice.rules]
+            {rules}
+
+            [matrix]
+            homeserver = "http://localhost:8008"
+            server_name = "test.local"
+        "#
+    ))
+    .expect("Should parse test config")
+}
+
+/// A state event in `!room:test.local` sent by `@alice:test.local`.
+pub(crate) fn state_event(event_type: &str, state_key: &str, content: Value) -> Value {
+    json!({
+        "type": event_type,
+        "state_key": state_key,
+        "content": content,
+        "event_id": "$event:test.local",
+        "sender": "@alice:test.local",
+        "origin_server_ts": 1,
+        "room_id": "!room:test.local",
+    })
+}
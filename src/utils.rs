@@ -8,6 +8,87 @@ pub fn slugify(s: &str) -> String {
     SLUG_REGEX.replace_all(s, "-").to_string().to_lowercase()
 }
 
+/// Compares two byte strings without exiting early on the first mismatch.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Decodes `%XX` escapes in a path segment, `None` if they're malformed or
+/// don't decode to UTF-8.
+pub fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Matches a value against a `*` and `?` glob. With `escapes`, a backslash
+/// makes the next character literal, as in redis patterns.
+pub fn glob_match(pattern: &str, value: &str, escapes: bool) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+
+    let (mut p, mut v) = (0, 0);
+    // where the last `*` was, and how much of the value it has taken
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                v += 1;
+                continue;
+            }
+            Some('\\') if escapes && pattern.get(p + 1) == Some(&value[v]) => {
+                p += 2;
+                v += 1;
+                continue;
+            }
+            Some(&c) if c == value[v] && !(escapes && c == '\\') => {
+                p += 1;
+                v += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        match backtrack {
+            Some((star, taken)) => {
+                p = star + 1;
+                v = taken + 1;
+                backtrack = Some((star, taken + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 pub fn room_alias_like(alias: &str) -> bool {
     let parts: Vec<&str> = alias.split(':').collect();
     parts.len() == 2 && !parts[0].is_empty() && !parts[1].is_empty() && !alias.starts_with('!')
@@ -99,3 +180,35 @@ fn is_valid_hostname(hostname: &str) -> bool {
 
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(
+            "proxy_request*",
+            "proxy_request:http://localhost",
+            true
+        ));
+        assert!(glob_match(
+            "*!room:test.local*",
+            "public_policy:!room:test.local",
+            true
+        ));
+        assert!(glob_match("a\\*b", "a*b", true));
+        assert!(!glob_match("a\\*b", "axb", true));
+        assert!(glob_match("a\\*b", "a\\xb", false));
+        assert!(glob_match("space_?ooms*", "space_rooms:music", true));
+        assert!(!glob_match("public_rooms", "public_spaces", true));
+
+        assert!(glob_match("@spam:*", "@spam:evil.test", false));
+        assert!(glob_match("*.evil.test", "a.evil.test", false));
+        assert!(!glob_match("*.evil.test", "evil.test", false));
+        assert!(glob_match("@user?:test", "@user1:test", false));
+        assert!(glob_match("*", "", false));
+        assert!(!glob_match("@alice:test", "@alice:test.local", false));
+        assert!(glob_match("a*b*c", "aXbYbZc", false));
+    }
+}